@@ -0,0 +1,98 @@
+// which parts of a program ran: per ROM address, then per .asm and .vm line, as lcov or annotated source
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::debug_info::{self, DebugInfo};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    // executions per ROM address
+    counts: Vec<u64>,
+}
+
+// executions per 1-based line of a source file, for the lines that have code
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileCoverage {
+    pub path: PathBuf,
+    pub lines: BTreeMap<usize, u64>,
+}
+
+impl FileCoverage {
+    pub fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|&&hits| hits > 0).count()
+    }
+}
+
+impl Coverage {
+    pub fn new(rom_size: usize) -> Self {
+        Self { counts: vec![0; rom_size] }
+    }
+
+    // call it with the address of each instruction that runs
+    pub fn record(&mut self, pc: u16) {
+        if let Some(count) = self.counts.get_mut(pc as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    // every .asm line with an instruction on it
+    pub fn asm_lines(&self, info: &DebugInfo) -> FileCoverage {
+        let mut lines = BTreeMap::new();
+        for (address, &line) in info.rom_lines.iter().enumerate() {
+            *lines.entry(line + 1).or_default() += self.counts.get(address).copied().unwrap_or(0);
+        }
+        FileCoverage { path: info.path.clone(), lines }
+    }
+
+    // every VM command the translator left a `// Foo.vm:12: ...` comment for, counting how many times
+    // its first instruction ran; the .vm files are looked up next to the .asm file
+    pub fn vm_lines(&self, info: &DebugInfo) -> Vec<FileCoverage> {
+        let dir = info.path.parent().map_or(PathBuf::new(), |dir| dir.to_path_buf());
+        let mut files: BTreeMap<&str, BTreeMap<usize, u64>> = BTreeMap::new();
+        let mut previous = None;
+        for (address, annotation) in info.annotations().into_iter().enumerate() {
+            let Some((comment_line, annotation)) = annotation else { continue };
+            if previous == Some(comment_line) {
+                continue;
+            }
+            previous = Some(comment_line);
+            if let Some(location) = debug_info::vm_location(annotation) {
+                let hits = self.counts.get(address).copied().unwrap_or(0);
+                *files.entry(location.file).or_default().entry(location.line).or_default() += hits;
+            }
+        }
+        files.into_iter().map(|(file, lines)| FileCoverage { path: dir.join(file), lines }).collect()
+    }
+}
+
+// the tracefile format lcov and genhtml read
+pub fn to_lcov(files: &[FileCoverage]) -> String {
+    let mut text = String::from("TN:\n");
+    for file in files {
+        text.push_str(&format!("SF:{}\n", file.path.display()));
+        for (line, hits) in &file.lines {
+            text.push_str(&format!("DA:{line},{hits}\n"));
+        }
+        text.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", file.lines.len(), file.lines_hit()));
+    }
+    text
+}
+
+// the source with each line prefixed by how many times it ran, gcov style: `-` for lines
+// without code and `#####` for code that never ran
+pub fn annotate(file: &FileCoverage, source: &str) -> String {
+    let mut text = String::new();
+    for (idx, line) in source.lines().enumerate() {
+        let hits = match file.lines.get(&(idx + 1)) {
+            None => "-".to_string(),
+            Some(0) => "#####".to_string(),
+            Some(hits) => hits.to_string(),
+        };
+        text.push_str(&format!("{hits:>9}: {:>5}: {line}\n", idx + 1));
+    }
+    text
+}
@@ -0,0 +1,377 @@
+use std::fmt;
+use std::sync::Arc;
+
+use assembler::instruction::Instruction;
+
+use crate::decoded::{self, DecodedRom};
+use crate::device::Device;
+use crate::snapshot::DeviceState;
+
+pub const RAM_SIZE: usize = 32768;
+pub const SCREEN: u16 = 16384;
+pub const SCREEN_END: u16 = 24575;
+pub const KBD: u16 = 24576;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulatorError {
+    // the word at `pc` isn't an A instruction nor a known C instruction
+    InvalidInstruction { pc: u16, word: u16 },
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::InvalidInstruction { pc, word } => write!(f, "ROM[{pc}]: invalid instruction {word:016b}"),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Halted,
+    CycleLimit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RamRead {
+    pub address: u16,
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RamWrite {
+    pub address: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+// what a single instruction did to RAM
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryAccess {
+    // where the instruction was
+    pub pc: u16,
+    pub read: Option<RamRead>,
+    pub write: Option<RamWrite>,
+}
+
+// a device and where it starts
+struct Attached {
+    start: u16,
+    device: Box<dyn Device>,
+}
+
+pub struct Cpu {
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub cycles: u64,
+    rom: Vec<u16>,
+    // the same program, decoded; shared between clones since the ROM never changes
+    decoded: Arc<DecodedRom>,
+    // SCREEN and KBD are part of it, at their usual addresses
+    ram: Box<[u16]>,
+    devices: Vec<Attached>,
+    // per RAM address, 1 + the index of the device there, or 0 for plain RAM
+    device_map: Box<[u8]>,
+    // set when a device said the program is done
+    exit_code: Option<u16>,
+}
+
+impl Cpu {
+    pub fn new(rom: Vec<u16>) -> Self {
+        Self {
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+            decoded: Arc::new(DecodedRom::new(&rom)),
+            rom,
+            ram: vec![0; RAM_SIZE].into_boxed_slice(),
+            devices: vec![],
+            device_map: vec![0; RAM_SIZE].into_boxed_slice(),
+            exit_code: None,
+        }
+    }
+
+    // maps a device at `start` and on for its size; it can't cover SCREEN or KBD, nor another device
+    pub fn attach(&mut self, start: u16, device: Box<dyn Device>) -> Result<(), String> {
+        let end = start as usize + device.size() as usize;
+        if device.size() == 0 || end > RAM_SIZE {
+            return Err(format!("{} at {start} doesn't fit in the address space", device.name()));
+        }
+        if start <= KBD && end > SCREEN as usize {
+            return Err(format!("{} at {start} would cover SCREEN or KBD", device.name()));
+        }
+        if let Some(&taken) = self.device_map[start as usize..end].iter().find(|&&idx| idx != 0) {
+            return Err(format!("{} at {start} would cover {}", device.name(), self.devices[taken as usize - 1].device.name()));
+        }
+        if self.devices.len() == u8::MAX as usize {
+            return Err("too many devices".to_string());
+        }
+        self.devices.push(Attached { start, device });
+        self.device_map[start as usize..end].fill(self.devices.len() as u8);
+        Ok(())
+    }
+
+    // the devices, with where they start
+    pub fn devices(&self) -> impl Iterator<Item = (u16, &dyn Device)> {
+        self.devices.iter().map(|attached| (attached.start, attached.device.as_ref()))
+    }
+
+    // what the program gave a device to stop it, see `device::ExitPort`
+    pub fn exit_code(&self) -> Option<u16> {
+        self.exit_code
+    }
+
+    // for restoring snapshots: puts the devices, which have to be the same kinds at the same places,
+    // back in their saved states, and the exit code with them
+    pub(crate) fn restore_devices(&mut self, states: &[DeviceState]) -> Result<(), String> {
+        let same_devices = states.len() == self.devices.len()
+            && states.iter().zip(&self.devices).all(|(saved, attached)| saved.start == attached.start && saved.name == attached.device.name());
+        if !same_devices {
+            return Err("it was taken with other devices attached".to_string());
+        }
+        for (saved, attached) in states.iter().zip(&mut self.devices) {
+            attached.device.restore(&saved.state)?;
+        }
+        self.exit_code = self.devices.iter().find_map(|attached| attached.device.exit_code());
+        Ok(())
+    }
+
+    fn device_at(&self, address: u16) -> Option<usize> {
+        match self.device_map[address as usize % RAM_SIZE] {
+            0 => None,
+            idx => Some(idx as usize - 1),
+        }
+    }
+
+    // what the program reads, which may be a device's read with its side effects, unlike `read`
+    fn load(&mut self, address: u16) -> u16 {
+        match self.device_at(address) {
+            Some(idx) => {
+                let attached = &mut self.devices[idx];
+                attached.device.read(address % RAM_SIZE as u16 - attached.start, self.cycles)
+            },
+            None => self.ram[address as usize % RAM_SIZE],
+        }
+    }
+
+    pub fn rom(&self) -> &[u16] {
+        &self.rom
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+
+    // for restoring snapshots, unlike `write` this can change the keyboard register too
+    pub(crate) fn ram_mut(&mut self) -> &mut [u16] {
+        &mut self.ram
+    }
+
+    // puts a word back for going back in time; devices can't be rewound so they're left alone
+    pub(crate) fn restore_word(&mut self, address: u16, value: u16) {
+        if self.device_at(address).is_none() {
+            self.ram[address as usize % RAM_SIZE] = value;
+        }
+    }
+
+    // devices are peeked at, so looking doesn't change anything
+    pub fn read(&self, address: u16) -> u16 {
+        match self.device_at(address) {
+            Some(idx) => {
+                let attached = &self.devices[idx];
+                attached.device.peek(address % RAM_SIZE as u16 - attached.start, self.cycles)
+            },
+            None => self.ram[address as usize % RAM_SIZE],
+        }
+    }
+
+    // the keyboard register is read-only for the program, see `set_key` for the host side
+    pub fn write(&mut self, address: u16, value: u16) {
+        // the RAM wraps around, so KBD + 32768 is the keyboard too
+        let address = address % RAM_SIZE as u16;
+        if let Some(idx) = self.device_at(address) {
+            let attached = &mut self.devices[idx];
+            attached.device.write(address - attached.start, value, self.cycles);
+            self.exit_code = self.exit_code.or(attached.device.exit_code());
+        } else if address != KBD {
+            self.ram[address as usize] = value;
+        }
+    }
+
+    pub fn screen(&self) -> &[u16] {
+        &self.ram[SCREEN as usize..=SCREEN_END as usize]
+    }
+
+    pub fn key(&self) -> u16 {
+        self.ram[KBD as usize]
+    }
+
+    // the Hack key code currently held down, 0 for none
+    pub fn set_key(&mut self, key: u16) {
+        self.ram[KBD as usize] = key;
+    }
+
+    // back to the state right after loading the ROM, keeping the devices
+    pub fn reset(&mut self) {
+        let mut cpu = Self::new(std::mem::take(&mut self.rom));
+        cpu.devices = std::mem::take(&mut self.devices);
+        cpu.device_map = std::mem::take(&mut self.device_map);
+        for attached in &mut cpu.devices {
+            attached.device.reset();
+        }
+        *self = cpu;
+    }
+
+    // the program is done when it runs off the end of the ROM, sits in the usual
+    // `(END) @END 0;JMP` loop at the end of it, or gave a device its exit code
+    pub fn is_halted(&self) -> bool {
+        let pc = self.pc as usize;
+        if pc >= self.rom.len() || self.exit_code.is_some() {
+            return true;
+        }
+        self.rom[pc] == self.pc && self.rom.get(pc + 1) == Some(&decoded::end_loop_jump())
+    }
+
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        self.step_traced().map(|_| ())
+    }
+
+    // like `step`, also telling what the instruction read from and wrote to RAM
+    pub fn step_traced(&mut self) -> Result<MemoryAccess, EmulatorError> {
+        let word = self.rom.get(self.pc as usize).copied().unwrap_or(0);
+        let instruction = self.decoded.get(self.pc).ok_or(EmulatorError::InvalidInstruction { pc: self.pc, word })?;
+        Ok(self.execute(instruction))
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> MemoryAccess {
+        self.cycles += 1;
+        let mut access = MemoryAccess { pc: self.pc, ..Default::default() };
+        match instruction {
+            Instruction::A(value) => {
+                self.a = value;
+                self.pc = self.pc.wrapping_add(1);
+            },
+            Instruction::C(c_instr) => {
+                // everything reads the registers from before this instruction, like the hardware does
+                let address = self.a;
+                let x = if c_instr.switch_a_for_m {
+                    let value = self.load(address);
+                    access.read = Some(RamRead { address, value });
+                    value
+                } else {
+                    address
+                };
+                let out = c_instr.comp.compute(self.d, x);
+                if c_instr.save_comp_to.m {
+                    access.write = Some(RamWrite { address, old: self.read(address), new: out });
+                    self.write(address, out);
+                }
+                if c_instr.save_comp_to.a { self.a = out; }
+                if c_instr.save_comp_to.d { self.d = out; }
+                self.pc = if c_instr.jump_if.should_jump(out) { address } else { self.pc.wrapping_add(1) };
+            },
+        }
+        access
+    }
+
+    // `step` over and over, only a lot faster: whole blocks run at once from the decoded ROM, with
+    // the registers kept in locals; `DEVICES` is whether any are attached, so RAM accesses only
+    // check for them when they have to
+    fn run_blocks<const DEVICES: bool>(&mut self, end: u64) -> Result<StopReason, EmulatorError> {
+        let decoded = Arc::clone(&self.decoded);
+        loop {
+            if self.is_halted() {
+                return Ok(StopReason::Halted);
+            }
+            if self.cycles >= end {
+                return Ok(StopReason::CycleLimit);
+            }
+            let block = decoded.block(self.pc);
+            let block = &block[..block.len().min((end - self.cycles).min(usize::MAX as u64) as usize)];
+            let (mut a, mut d, mut pc) = (self.a, self.d, self.pc);
+            let start = self.cycles;
+            let mut ran = 0;
+            let mut error = None;
+            for instruction in block {
+                match instruction {
+                    Some(Instruction::A(value)) => {
+                        a = *value;
+                        pc = pc.wrapping_add(1);
+                    },
+                    Some(Instruction::C(c_instr)) => {
+                        let address = a;
+                        let cell = address as usize % RAM_SIZE;
+                        let device = DEVICES && self.device_map[cell] != 0;
+                        if device {
+                            // devices get the cycle of the instruction, like `step` gives them
+                            self.cycles = start + ran + 1;
+                        }
+                        let x = match c_instr.switch_a_for_m {
+                            true if device => self.load(address),
+                            true => self.ram[cell],
+                            false => address,
+                        };
+                        let out = c_instr.comp.compute(d, x);
+                        if c_instr.save_comp_to.m {
+                            if device {
+                                self.write(address, out);
+                            } else if cell != KBD as usize {
+                                self.ram[cell] = out;
+                            }
+                        }
+                        if c_instr.save_comp_to.a { a = out; }
+                        if c_instr.save_comp_to.d { d = out; }
+                        pc = if c_instr.jump_if.should_jump(out) { address } else { pc.wrapping_add(1) };
+                        if device && self.exit_code.is_some() {
+                            ran += 1;
+                            break;
+                        }
+                    },
+                    None => {
+                        error = Some(EmulatorError::InvalidInstruction { pc, word: self.rom[pc as usize] });
+                        break;
+                    },
+                }
+                ran += 1;
+            }
+            (self.a, self.d, self.pc) = (a, d, pc);
+            self.cycles = start + ran;
+            if let Some(error) = error {
+                return Err(error);
+            }
+        }
+    }
+
+    pub fn run(&mut self, max_cycles: u64) -> Result<StopReason, EmulatorError> {
+        let end = self.cycles.saturating_add(max_cycles);
+        if self.devices.is_empty() { self.run_blocks::<false>(end) } else { self.run_blocks::<true>(end) }
+    }
+
+    pub fn run_until_halt(&mut self) -> Result<(), EmulatorError> {
+        self.run(u64::MAX).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyboard_aliases_are_read_only() {
+        let mut cpu = Cpu::new(vec![]);
+        cpu.set_key(65);
+        cpu.write(KBD, 1);
+        cpu.write(KBD.wrapping_add(RAM_SIZE as u16), 2);
+        assert_eq!(cpu.key(), 65);
+        // the same goes for programs run a block at a time
+        let program = assembler::assemble("@24576\nD=A\n@32767\nD=D+A\nA=D+1\nM=1\n(END)\n@END\n0;JMP").unwrap();
+        let mut cpu = Cpu::new(program);
+        cpu.set_key(65);
+        cpu.run_until_halt().unwrap();
+        assert_eq!(cpu.key(), 65);
+    }
+}
@@ -0,0 +1,622 @@
+// a Debug Adapter Protocol server, so editors can debug Hack programs at the .asm level or, for
+// translated programs, the .vm level (through the `// Foo.vm:12: push local 0` comments the translator writes)
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::{fs, thread};
+
+use assembler::instruction::{Instruction, JumpIf};
+use assembler::symbols::SymbolKind;
+use assembler::AssembleError;
+use serde_json::{json, Value};
+
+use crate::debug_info::{self, load_program_with_debug_info};
+use crate::debugger::{Debugger, Stop};
+use crate::keyboard::KeyScript;
+use crate::snapshot::Snapshot;
+use crate::Cpu;
+
+// how many cycles a run goes between checks for a `pause`
+const CYCLES_PER_POLL: u64 = 100_000;
+// the VM stack shows its top entries only past this
+const MAX_STACK_VARIABLES: u16 = 256;
+// how much of the this/that segments to show, as their size isn't known
+const SEGMENT_WINDOW: u16 = 8;
+
+// the variables scopes, by their `variablesReference`
+const REGISTERS: u64 = 1;
+const STACK: u64 = 2;
+const LOCAL: u64 = 3;
+const ARGUMENT: u64 = 4;
+const THIS: u64 = 5;
+const THAT: u64 = 6;
+
+// the predefined pointers, at the bottom of the RAM
+const SP: u16 = 0;
+const LCL: u16 = 1;
+const ARG: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granularity {
+    // a step runs one VM command, and locations are .vm lines
+    Vm,
+    // a step runs one instruction, and locations are .asm lines
+    Asm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Motion {
+    Continue,
+    Next,
+    StepIn,
+    StepOut,
+    // a single instruction, whatever the granularity
+    Instruction,
+    StepBack,
+    InstructionBack,
+    ReverseContinue,
+}
+
+// what to do once a request has been answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    None,
+    // the client can configure breakpoints from then on
+    Launched,
+    Entry,
+    Run(Motion),
+    Quit,
+}
+
+// a VM function, from its `function Foo.bar n` label
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Function {
+    address: u16,
+    name: String,
+    locals: u16,
+}
+
+// the program being debugged
+struct Session {
+    debugger: Debugger,
+    granularity: Granularity,
+    // per ROM address, what a step runs through: the line of the VM command's comment in the .asm,
+    // or the instruction's own line, so both are .asm lines; the ROM address without debug info
+    statements: Vec<usize>,
+    // per ROM address, the .vm file and 1-based line its VM command came from
+    vm_lines: Vec<Option<(String, usize)>>,
+    // per ROM address, where stepping over it ends up: after the code of a VM `call`, or right
+    // after an unconditional jump at the .asm level
+    returns_to: Vec<Option<u16>>,
+    // sorted by address
+    functions: Vec<Function>,
+    // source path -> the debugger breakpoints set in it, replaced on every `setBreakpoints`
+    breakpoints: HashMap<PathBuf, Vec<usize>>,
+}
+
+pub struct DapServer<W: Write> {
+    out: W,
+    messages: Receiver<Value>,
+    // requests that came in while the program was running, handled once it stops
+    pending: VecDeque<Value>,
+    seq: u64,
+    session: Option<Session>,
+    stop_on_entry: bool,
+}
+
+// reads one `Content-Length: n` framed message, or None at the end of the input
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "a message without a Content-Length"));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+// reads messages on another thread, so a running program can still be paused
+pub fn spawn_reader(mut input: impl BufRead + Send + 'static) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn value_of(cpu: &Cpu, address: u16) -> String {
+    (cpu.read(address) as i16).to_string()
+}
+
+fn variable(name: impl Into<String>, value: String) -> Value {
+    json!({ "name": name.into(), "value": value, "variablesReference": 0 })
+}
+
+impl Session {
+    fn new(debugger: Debugger, granularity: Option<Granularity>) -> Self {
+        let rom = debugger.cpu.rom();
+        let info = debugger.info.as_ref();
+        let annotations = info.map_or(vec![], |info| info.annotations());
+        let vm_lines = annotations.iter()
+            .map(|annotation| annotation.and_then(|(_, text)| debug_info::vm_location(text)).map(|location| (location.file.to_string(), location.line)))
+            .collect::<Vec<_>>();
+        // translated programs step through VM commands unless told otherwise
+        let granularity = granularity.unwrap_or(if vm_lines.iter().any(Option::is_some) { Granularity::Vm } else { Granularity::Asm });
+
+        let statements = (0..rom.len())
+            .map(|address| match (granularity, annotations.get(address).copied().flatten(), info) {
+                (Granularity::Vm, Some((line, _)), _) => line,
+                (_, _, Some(info)) => info.rom_lines[address],
+                (_, _, None) => address,
+            })
+            .collect::<Vec<_>>();
+
+        let mut returns_to = vec![None; rom.len()];
+        for address in 0..rom.len() {
+            returns_to[address] = match granularity {
+                Granularity::Vm => {
+                    let is_call = annotations.get(address).copied().flatten()
+                        .and_then(|(_, text)| debug_info::vm_location(text))
+                        .is_some_and(|location| location.command.starts_with("call "));
+                    // the return address is right past the call's code
+                    let end = (address + 1..rom.len()).find(|&next| statements[next] != statements[address]);
+                    end.filter(|_| is_call).map(|end| end as u16)
+                },
+                Granularity::Asm => {
+                    let jumps = matches!(Instruction::decode(rom[address]), Some(Instruction::C(c_instr)) if c_instr.jump_if == JumpIf::Jmp);
+                    jumps.then_some(address as u16 + 1)
+                },
+            };
+        }
+
+        let mut functions = info.map_or(vec![], |info| {
+            info.symbols.iter()
+                .filter(|(name, symbol)| symbol.kind == SymbolKind::Label && debug_info::is_vm_function(name))
+                .map(|(name, symbol)| {
+                    // `function Foo.bar 2` says how many locals it has
+                    let locals = annotations.get(symbol.address as usize).copied().flatten()
+                        .and_then(|(_, text)| debug_info::vm_location(text))
+                        .filter(|location| location.command.starts_with("function "))
+                        .and_then(|location| location.command.split_whitespace().nth(2)?.parse().ok())
+                        .unwrap_or(0);
+                    Function { address: symbol.address, name: name.clone(), locals }
+                })
+                .collect()
+        });
+        functions.sort_by_key(|function| function.address);
+
+        Self { debugger, granularity, statements, vm_lines, returns_to, functions, breakpoints: HashMap::new() }
+    }
+
+    fn cpu(&self) -> &Cpu {
+        &self.debugger.cpu
+    }
+
+    // the source file and 1-based line of a ROM address, at the session's granularity
+    fn location(&self, address: u16) -> Option<(PathBuf, usize)> {
+        let info = self.debugger.info.as_ref()?;
+        if self.granularity == Granularity::Vm {
+            if let Some((file, line)) = self.vm_lines.get(address as usize).cloned().flatten() {
+                let dir = info.path.parent().map_or(PathBuf::new(), |dir| dir.to_path_buf());
+                return Some((dir.join(file), line));
+            }
+        }
+        Some((info.path.clone(), info.rom_lines.get(address as usize)? + 1))
+    }
+
+    // the first instruction of a source line, or of the next line that has code
+    fn address_of_line(&self, path: &Path, line: usize) -> Option<u16> {
+        let info = self.debugger.info.as_ref()?;
+        let file_name = path.file_name()?.to_str()?;
+        if path.extension().is_some_and(|ext| ext == "vm") {
+            return self.vm_lines.iter().enumerate()
+                .filter_map(|(address, location)| location.as_ref().map(|(file, vm_line)| (file, *vm_line, address)))
+                .filter(|&(file, vm_line, _)| file == file_name && vm_line >= line)
+                .min_by_key(|&(_, vm_line, address)| (vm_line, address))
+                .map(|(_, _, address)| address as u16);
+        }
+        if info.path.file_name()?.to_str()? != file_name {
+            return None;
+        }
+        info.rom_address_of_line(line.checked_sub(1)?)
+    }
+
+    // the VM function the PC is in
+    fn function(&self) -> Option<&Function> {
+        self.functions.iter().rev().find(|function| function.address <= self.cpu().pc)
+    }
+
+    fn set_breakpoints(&mut self, path: &Path, requested: &[Value]) -> Vec<Value> {
+        for id in self.breakpoints.remove(path).unwrap_or_default() {
+            let _ = self.debugger.delete_breakpoint(id);
+        }
+        let mut ids = vec![];
+        let mut replies = vec![];
+        for breakpoint in requested {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            let Some(address) = self.address_of_line(path, line) else {
+                replies.push(json!({ "verified": false, "line": line, "message": "no code on or after this line" }));
+                continue;
+            };
+            let spec = match breakpoint["condition"].as_str().filter(|condition| !condition.trim().is_empty()) {
+                Some(condition) => format!("{address} if {condition}"),
+                None => address.to_string(),
+            };
+            let actual_line = self.location(address).map_or(line, |(_, line)| line);
+            match self.debugger.add_breakpoint(&spec) {
+                Ok(id) => {
+                    ids.push(id);
+                    replies.push(json!({ "id": id, "verified": true, "line": actual_line }));
+                },
+                Err(message) => replies.push(json!({ "verified": false, "line": line, "message": message })),
+            }
+        }
+        self.breakpoints.insert(path.to_path_buf(), ids);
+        replies
+    }
+
+    fn step_in(&mut self, interrupted: &mut impl FnMut() -> bool) -> Option<Stop> {
+        let statements = &self.statements;
+        let start = statements.get(self.debugger.cpu.pc as usize).copied();
+        run(&mut self.debugger, |cpu| statements.get(cpu.pc as usize).copied() != start, interrupted)
+    }
+
+    // the caller's frame is back when LCL is what it was, which tells a recursive call's return
+    // from the one we're waiting for
+    fn run_to_return(&mut self, address: u16, lcl: u16, interrupted: &mut impl FnMut() -> bool) -> Option<Stop> {
+        run(&mut self.debugger, |cpu| cpu.pc == address && cpu.read(LCL) == lcl, interrupted)
+    }
+
+    fn step_back(&mut self) -> Stop {
+        let statement = |session: &Session| session.statements.get(session.cpu().pc as usize).copied();
+        let start = statement(self);
+        loop {
+            if self.debugger.reverse_step().is_none() {
+                return Stop::NoHistory;
+            }
+            if statement(self) != start {
+                break;
+            }
+        }
+        // then back to the start of that statement
+        let target = statement(self);
+        while self.debugger.reverse_step().is_some() {
+            if statement(self) != target {
+                let _ = self.debugger.step();
+                break;
+            }
+        }
+        Stop::Done
+    }
+
+    fn perform(&mut self, motion: Motion, interrupted: &mut impl FnMut() -> bool) -> Option<Stop> {
+        let cpu = &self.debugger.cpu;
+        let (pc, lcl) = (cpu.pc, cpu.read(LCL));
+        match motion {
+            Motion::Continue => run(&mut self.debugger, |_| false, interrupted),
+            Motion::StepIn => self.step_in(interrupted),
+            Motion::Next => match self.returns_to.get(pc as usize).copied().flatten() {
+                Some(address) => self.run_to_return(address, lcl, interrupted),
+                None => self.step_in(interrupted),
+            },
+            // the frame `call` pushed has the return address 5 words below LCL, and the caller's LCL 4 below
+            Motion::StepOut if lcl >= 5 => {
+                let (address, caller_lcl) = (cpu.read(lcl - 5), cpu.read(lcl - 4));
+                self.run_to_return(address, caller_lcl, interrupted)
+            },
+            Motion::StepOut => self.step_in(interrupted),
+            Motion::Instruction => run(&mut self.debugger, |_| true, interrupted),
+            Motion::StepBack => Some(self.step_back()),
+            Motion::InstructionBack => Some(self.debugger.reverse_step().map_or(Stop::NoHistory, |_| Stop::Done)),
+            Motion::ReverseContinue => Some(self.debugger.reverse_cont()),
+        }
+    }
+
+    fn stack_frame(&self) -> Value {
+        let pc = self.cpu().pc;
+        let name = match (self.function(), self.debugger.info.as_ref().and_then(|info| info.label_region(pc))) {
+            (Some(function), _) => function.name.clone(),
+            (None, Some((label, _))) => label.to_string(),
+            (None, None) => format!("ROM[{pc}]"),
+        };
+        let mut frame = json!({ "id": 0, "name": name, "line": 0, "column": 0, "instructionPointerReference": pc.to_string() });
+        if let Some((path, line)) = self.location(pc) {
+            let source_name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+            frame["source"] = json!({ "name": source_name, "path": path.to_string_lossy() });
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+        }
+        frame
+    }
+
+    fn variables(&self, reference: u64) -> Vec<Value> {
+        let cpu = self.cpu();
+        let pointer = |address: u16| cpu.read(address);
+        let (lcl, arg) = (pointer(LCL), pointer(ARG));
+        let segment = |name: &str, base: u16, len: u16| -> Vec<Value> {
+            (0..len).map(|idx| variable(format!("{name}[{idx}]"), value_of(cpu, base.wrapping_add(idx)))).collect()
+        };
+        match reference {
+            REGISTERS => {
+                let mut variables = vec![
+                    variable("A", (cpu.a as i16).to_string()),
+                    variable("D", (cpu.d as i16).to_string()),
+                    variable("PC", cpu.pc.to_string()),
+                ];
+                for (address, name) in ["SP", "LCL", "ARG", "THIS", "THAT"].iter().enumerate() {
+                    variables.push(variable(*name, pointer(address as u16).to_string()));
+                }
+                variables
+            },
+            // the current function's working stack, above its locals; all of it outside functions
+            STACK => {
+                let sp = pointer(SP);
+                let base = self.function().map_or(256, |function| lcl.wrapping_add(function.locals)).max(sp.saturating_sub(MAX_STACK_VARIABLES));
+                (base..sp).map(|address| variable(format!("RAM[{address}]"), value_of(cpu, address))).collect()
+            },
+            LOCAL => segment("local", lcl, self.function().map_or(0, |function| function.locals)),
+            // the caller's arguments end where the frame `call` pushed starts
+            ARGUMENT => segment("argument", arg, lcl.saturating_sub(arg).saturating_sub(5)),
+            THIS | THAT => {
+                let (name, base) = if reference == THIS { ("this", pointer(3)) } else { ("that", pointer(4)) };
+                segment(name, base, if base == 0 { 0 } else { SEGMENT_WINDOW })
+            },
+            _ => vec![],
+        }
+    }
+}
+
+// runs until `done` says so or something stops it, checking `interrupted` every so often;
+// None when it was interrupted
+fn run(debugger: &mut Debugger, mut done: impl FnMut(&Cpu) -> bool, interrupted: &mut impl FnMut() -> bool) -> Option<Stop> {
+    loop {
+        let end = debugger.cpu.cycles + CYCLES_PER_POLL;
+        let mut finished = false;
+        let stop = debugger.run_until(|cpu| {
+            finished = done(cpu);
+            finished || cpu.cycles >= end
+        });
+        if finished || stop != Stop::Done {
+            return Some(stop);
+        }
+        if interrupted() {
+            return None;
+        }
+    }
+}
+
+fn stopped_event(stop: Option<Stop>) -> Value {
+    let (reason, text) = match stop {
+        None => ("pause", None),
+        Some(Stop::Breakpoint(id)) => return json!({ "reason": "breakpoint", "threadId": 1, "allThreadsStopped": true, "hitBreakpointIds": [id] }),
+        Some(Stop::Watchpoint(_)) => ("data breakpoint", None),
+        Some(Stop::Halted) => unreachable!("a halted program exits instead of stopping"),
+        Some(Stop::Done) => ("step", None),
+        Some(Stop::NoHistory) => ("step", Some("no history before this".to_string())),
+        Some(Stop::Error(error)) => ("exception", Some(error.to_string())),
+    };
+    let mut event = json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true });
+    if let Some(text) = text {
+        event["description"] = json!(text);
+        event["text"] = json!(text);
+    }
+    event
+}
+
+fn launch(args: &Value) -> Result<Session, String> {
+    let program = args["program"].as_str().ok_or("`program` is missing")?;
+    let (rom, info) = load_program_with_debug_info(program).map_err(|error| match error {
+        AssembleError::Io(error) => format!("{program}: {error}"),
+        AssembleError::Syntax(errors) => errors.iter().map(|error| format!("{program}:{error}")).collect::<Vec<_>>().join("\n"),
+    })?;
+    let mut cpu = Cpu::new(rom);
+    if let Some(path) = args["snapshot"].as_str() {
+        Snapshot::load(path).and_then(|snapshot| snapshot.restore(&mut cpu)).map_err(|error| format!("{path}: {error}"))?;
+    }
+    let keys = match args["keys"].as_str() {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|error| format!("{path}: {error}"))?;
+            KeyScript::parse(&text).map_err(|error| format!("{path}:{error}"))?
+        },
+        None => KeyScript::default(),
+    };
+    let granularity = match args["granularity"].as_str() {
+        Some("vm") => Some(Granularity::Vm),
+        Some("asm") => Some(Granularity::Asm),
+        Some(other) => return Err(format!("unknown granularity `{other}`, expected `vm` or `asm`")),
+        None => None,
+    };
+    Ok(Session::new(Debugger::new(cpu, info, keys), granularity))
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W, messages: Receiver<Value>) -> Self {
+        Self { out, messages, pending: VecDeque::new(), seq: 0, session: None, stop_on_entry: false }
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.out.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({ "type": "response", "request_seq": request["seq"], "command": request["command"], "success": result.is_ok() });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn session(&mut self) -> Result<&mut Session, String> {
+        self.session.as_mut().ok_or_else(|| "no program was launched".to_string())
+    }
+
+    // the body of the response, and what to do after sending it
+    fn request(&mut self, command: &str, args: &Value) -> Result<(Value, Action), String> {
+        let instruction = args["granularity"].as_str() == Some("instruction");
+        let motion = match command {
+            "continue" => Some(Motion::Continue),
+            "next" if instruction => Some(Motion::Instruction),
+            "next" => Some(Motion::Next),
+            "stepIn" if instruction => Some(Motion::Instruction),
+            "stepIn" => Some(Motion::StepIn),
+            "stepOut" => Some(Motion::StepOut),
+            "stepBack" if instruction => Some(Motion::InstructionBack),
+            "stepBack" => Some(Motion::StepBack),
+            "reverseContinue" => Some(Motion::ReverseContinue),
+            _ => None,
+        };
+        if let Some(motion) = motion {
+            self.session()?;
+            let body = if motion == Motion::Continue { json!({ "allThreadsContinued": true }) } else { Value::Null };
+            return Ok((body, Action::Run(motion)));
+        }
+
+        let body = match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsSteppingGranularity": true,
+                "supportsStepBack": true,
+                "supportsEvaluateForHovers": true,
+                "supportsTerminateRequest": true,
+            }),
+            "launch" => {
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                self.session = Some(launch(args)?);
+                return Ok((Value::Null, Action::Launched));
+            },
+            "configurationDone" => {
+                self.session()?;
+                let action = if self.stop_on_entry { Action::Entry } else { Action::Run(Motion::Continue) };
+                return Ok((Value::Null, action));
+            },
+            "setBreakpoints" => {
+                let path = PathBuf::from(args["source"]["path"].as_str().ok_or("the source has no path")?);
+                let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+                json!({ "breakpoints": self.session()?.set_breakpoints(&path, &requested) })
+            },
+            "setExceptionBreakpoints" => json!({ "breakpoints": [] }),
+            "threads" => json!({ "threads": [{ "id": 1, "name": "hack" }] }),
+            "stackTrace" => json!({ "stackFrames": [self.session()?.stack_frame()], "totalFrames": 1 }),
+            "scopes" => json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+                { "name": "Local", "variablesReference": LOCAL, "expensive": false },
+                { "name": "Argument", "variablesReference": ARGUMENT, "expensive": false },
+                { "name": "This", "variablesReference": THIS, "expensive": false },
+                { "name": "That", "variablesReference": THAT, "expensive": false },
+            ]}),
+            "variables" => {
+                let reference = args["variablesReference"].as_u64().unwrap_or(0);
+                json!({ "variables": self.session()?.variables(reference) })
+            },
+            // the same operands as the debugger's `print`: `A`, `D`, `PC`, `RAM[256]`, `SP`...
+            "evaluate" => {
+                let session = self.session()?;
+                let expression = args["expression"].as_str().unwrap_or("");
+                let value = session.debugger.parse_operand(expression)?.get(session.cpu());
+                json!({ "result": value.to_string(), "variablesReference": 0 })
+            },
+            // the program is stopped by now, or it would have been handled while running
+            "pause" => Value::Null,
+            "disconnect" | "terminate" => return Ok((Value::Null, Action::Quit)),
+            _ => return Err(format!("`{command}` isn't supported")),
+        };
+        Ok((body, Action::None))
+    }
+
+    // runs the program, keeping whatever requests come in meanwhile for later; a `pause`, or
+    // anything that ends the session, stops it
+    fn perform(&mut self, motion: Motion) -> io::Result<()> {
+        let Some(session) = self.session.as_mut() else { return Ok(()) };
+        let (messages, pending) = (&self.messages, &mut self.pending);
+        let mut interrupted = || {
+            let mut stop = false;
+            while let Ok(message) = messages.try_recv() {
+                stop |= matches!(message["command"].as_str(), Some("pause" | "disconnect" | "terminate"));
+                pending.push_back(message);
+            }
+            stop
+        };
+        let stop = session.perform(motion, &mut interrupted);
+        let exit_code = session.cpu().exit_code().unwrap_or(0);
+        // the `pause` is answered before saying it stopped
+        if let Some(idx) = self.pending.iter().position(|message| message["command"] == "pause") {
+            let pause = self.pending.remove(idx).unwrap();
+            self.respond(&pause, Ok(Value::Null))?;
+        }
+        match stop {
+            // the program is over, the client ends the session after these
+            Some(Stop::Halted) => {
+                self.event("exited", json!({ "exitCode": exit_code }))?;
+                self.event("terminated", Value::Null)
+            },
+            _ => self.event("stopped", stopped_event(stop)),
+        }
+    }
+
+    // handles one message, returning false once the session is over
+    fn handle(&mut self, message: &Value) -> io::Result<bool> {
+        if message["type"] != "request" {
+            return Ok(true);
+        }
+        let command = message["command"].as_str().unwrap_or("");
+        let result = self.request(command, &message["arguments"]);
+        let action = result.as_ref().map_or(Action::None, |(_, action)| *action);
+        self.respond(message, result.map(|(body, _)| body))?;
+        match action {
+            Action::None => {},
+            Action::Launched => self.event("initialized", Value::Null)?,
+            Action::Entry => self.event("stopped", json!({ "reason": "entry", "threadId": 1, "allThreadsStopped": true }))?,
+            Action::Run(motion) => self.perform(motion)?,
+            Action::Quit => {
+                self.event("terminated", Value::Null)?;
+                return Ok(false);
+            },
+        }
+        Ok(true)
+    }
+
+    // answers requests until the client disconnects or goes away
+    pub fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let message = match self.pending.pop_front() {
+                Some(message) => message,
+                None => match self.messages.recv() {
+                    Ok(message) => message,
+                    Err(_) => return Ok(()),
+                },
+            };
+            if !self.handle(&message)? {
+                return Ok(());
+            }
+        }
+    }
+}
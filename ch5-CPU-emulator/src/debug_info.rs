@@ -0,0 +1,141 @@
+// what we know about a program that was assembled from source: its symbols and where each instruction came from
+use std::{fs, path::{Path, PathBuf}};
+
+use assembler::{assemble_program, instruction::Instruction, read_hack, symbols::{SymbolKind, SymbolTable}, AssembleError};
+
+#[derive(Debug, Clone)]
+pub struct DebugInfo {
+    pub path: PathBuf,
+    pub symbols: SymbolTable,
+    // ROM address -> 0-based source line
+    pub rom_lines: Vec<usize>,
+    pub source: Vec<String>,
+}
+
+impl DebugInfo {
+    // the label declared right at this ROM address
+    pub fn label_at(&self, rom_address: u16) -> Option<&str> {
+        self.symbols.names_at(rom_address, &[SymbolKind::Label]).into_iter().next()
+    }
+
+    // the closest label at or before this ROM address, along with its address
+    pub fn label_region(&self, rom_address: u16) -> Option<(&str, u16)> {
+        self.symbols.iter()
+            .filter(|(_, symbol)| symbol.kind == SymbolKind::Label && symbol.address <= rom_address)
+            // on ties, the alphabetically first name, so the result doesn't depend on the hash map order
+            .max_by(|(a_name, a), (b_name, b)| a.address.cmp(&b.address).then(b_name.cmp(a_name)))
+            .map(|(name, symbol)| (name.as_str(), symbol.address))
+    }
+
+    // the name of a RAM address, preferring `SP`/`LCL`/... over `R0`/`R1`/...
+    pub fn ram_name(&self, address: u16) -> Option<&str> {
+        let names = self.symbols.names_at(address, &[SymbolKind::Predefined, SymbolKind::Variable]);
+        let is_register = |name: &&str| name.strip_prefix('R').is_some_and(|n| n.parse::<u8>().is_ok());
+        names.iter().find(|name| !is_register(name)).or(names.first()).copied()
+    }
+
+    // the 0-based line and text of the instruction at this ROM address
+    pub fn source_line(&self, rom_address: u16) -> Option<(usize, &str)> {
+        let line = *self.rom_lines.get(rom_address as usize)?;
+        Some((line, self.source.get(line).map_or("", |text| text.trim())))
+    }
+
+    // for each ROM address, the closest comment on a line of its own above the instruction; that's
+    // how the VM translator marks which VM command each instruction came from
+    // (0-based line, text without the slashes), like `source_line`
+    pub fn annotations(&self) -> Vec<Option<(usize, &str)>> {
+        let mut annotations = Vec::with_capacity(self.rom_lines.len());
+        let mut current = None;
+        let mut next_line = 0;
+        for &line in &self.rom_lines {
+            for (comment_line, text) in self.source.iter().enumerate().take(line + 1).skip(next_line) {
+                if let Some(comment) = text.trim().strip_prefix("//") {
+                    current = Some((comment_line, comment.trim()));
+                }
+            }
+            next_line = next_line.max(line + 1);
+            annotations.push(current);
+        }
+        annotations
+    }
+
+    // the first ROM address whose instruction comes from this line or a later one
+    pub fn rom_address_of_line(&self, line: usize) -> Option<u16> {
+        self.rom_lines.iter().position(|&rom_line| rom_line >= line).map(|address| address as u16)
+    }
+
+    // a label name or a plain number
+    pub fn resolve_rom(&self, name: &str) -> Option<u16> {
+        name.parse::<u16>().ok().or_else(|| {
+            self.symbols.get(name).filter(|symbol| symbol.kind == SymbolKind::Label).map(|symbol| symbol.address)
+        })
+    }
+
+    // a RAM symbol or a plain number
+    pub fn resolve_ram(&self, name: &str) -> Option<u16> {
+        name.parse::<u16>().ok().or_else(|| {
+            self.symbols.get(name).filter(|symbol| symbol.kind != SymbolKind::Label).map(|symbol| symbol.address)
+        })
+    }
+}
+
+// where a VM command came from, as the translator writes it above the command's code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmLocation<'a> {
+    pub file: &'a str,
+    // 1-based
+    pub line: usize,
+    pub command: &'a str,
+}
+
+// reads an annotation like `Foo.vm:12: push local 0`
+pub fn vm_location(annotation: &str) -> Option<VmLocation<'_>> {
+    let (file, rest) = annotation.split_once(".vm:")?;
+    let (line, command) = rest.split_once(':')?;
+    Some(VmLocation { file: &annotation[..file.len() + 3], line: line.parse().ok()?, command: command.trim() })
+}
+
+// VM functions are the labels the translator writes for `function Foo.bar n`, so they have a dot
+// and no `$`, unlike the labels inside them (`Foo.bar$LOOP`, `Foo.bar$ret.0`)
+pub fn is_vm_function(label: &str) -> bool {
+    label.contains('.') && !label.contains('$')
+}
+
+// like `load_program`, but .asm files also come back with their debug info
+pub fn load_program_with_debug_info(path: impl AsRef<Path>) -> Result<(Vec<u16>, Option<DebugInfo>), AssembleError> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("asm") => {
+            let program = assemble_program(&text).map_err(AssembleError::Syntax)?;
+            let info = DebugInfo {
+                path: path.to_path_buf(),
+                symbols: program.symbols,
+                rom_lines: program.source_lines,
+                source: text.lines().map(|line| line.to_string()).collect(),
+            };
+            Ok((program.words, Some(info)))
+        },
+        _ => read_hack(&text).map(|rom| (rom, None)).map_err(|error| AssembleError::Syntax(vec![error])),
+    }
+}
+
+// `ROM[12] LOOP+2: D=M    Max.asm:9: D=M // load`, or just `ROM[12]: D=M` without debug info
+pub fn describe_instruction(rom: &[u16], info: Option<&DebugInfo>, address: u16) -> String {
+    let word = rom.get(address as usize).copied().unwrap_or(0);
+    let disassembled = Instruction::decode(word).map_or_else(|| format!("<invalid {word:016b}>"), |instruction| instruction.to_string());
+    let mut text = format!("ROM[{address}]");
+    if let Some(info) = info {
+        if let Some((label, label_address)) = info.label_region(address) {
+            text.push_str(&if label_address == address { format!(" {label}") } else { format!(" {label}+{}", address - label_address) });
+        }
+        text.push_str(&format!(": {disassembled:<12}"));
+        if let Some((line, source)) = info.source_line(address) {
+            let file = info.path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+            text.push_str(&format!("  {file}:{}: {source}", line + 1));
+        }
+    } else {
+        text.push_str(&format!(": {disassembled}"));
+    }
+    text
+}
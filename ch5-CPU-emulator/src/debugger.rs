@@ -0,0 +1,508 @@
+// a line-oriented debugger on top of the emulator, gdb style
+use std::io::{self, prelude::*};
+
+use assembler::instruction::{Instruction, JumpIf};
+
+use crate::keyboard::KeyScript;
+use crate::script::Comparison;
+use crate::snapshot::Snapshot;
+use crate::watch::{self, WatchAction, WatchHit, WatchKind, Watchpoint};
+use crate::cpu::MemoryAccess;
+use crate::debug_info;
+use crate::history::{Delta, History};
+use crate::{Cpu, DebugInfo, EmulatorError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    A,
+    D,
+    PC,
+    Ram(u16),
+    Const(i64),
+}
+
+impl Operand {
+    pub fn get(&self, cpu: &Cpu) -> i64 {
+        match self {
+            Operand::A => cpu.a as i16 as i64,
+            Operand::D => cpu.d as i16 as i64,
+            Operand::PC => cpu.pc as i64,
+            Operand::Ram(address) => cpu.read(*address) as i16 as i64,
+            Operand::Const(value) => *value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    left: Operand,
+    comparison: Comparison,
+    right: Operand,
+}
+
+impl Condition {
+    pub fn holds(&self, cpu: &Cpu) -> bool {
+        self.comparison.holds(self.left.get(cpu), self.right.get(cpu))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Condition>,
+    // what the user typed, to show it back
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    // the id of the breakpoint that was hit
+    Breakpoint(usize),
+    Watchpoint(WatchHit),
+    Halted,
+    // a step, or stepping over a jump, finished
+    Done,
+    // going backwards, there's nothing left to undo
+    NoHistory,
+    Error(EmulatorError),
+}
+
+pub struct Debugger {
+    pub cpu: Cpu,
+    pub info: Option<DebugInfo>,
+    pub keys: KeyScript,
+    // indexed by breakpoint id, deleted ones are left as None so the ids stay the same
+    breakpoints: Vec<Option<Breakpoint>>,
+    // same as breakpoints, with their own ids
+    watchpoints: Vec<Option<Watchpoint>>,
+    // hits of `log` watchpoints since the last command
+    logged: Vec<WatchHit>,
+    pub history: History,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new(cpu: Cpu, info: Option<DebugInfo>, keys: KeyScript) -> Self {
+        Self { cpu, info, keys, breakpoints: vec![], watchpoints: vec![], logged: vec![], history: History::default(), last_command: String::new() }
+    }
+
+    // `RAM[256]`, `RAM[SP]`, `A`, `D`, `PC`, a number, or a RAM symbol which reads its value
+    pub fn parse_operand(&self, text: &str) -> Result<Operand, String> {
+        let text = text.trim();
+        match text {
+            "A" => return Ok(Operand::A),
+            "D" => return Ok(Operand::D),
+            "PC" => return Ok(Operand::PC),
+            _ => {},
+        }
+        if let Ok(value) = text.parse::<i64>() {
+            return Ok(Operand::Const(value));
+        }
+        let name = text.strip_prefix("RAM[").and_then(|rest| rest.strip_suffix(']')).unwrap_or(text);
+        self.resolve_ram(name).map(Operand::Ram)
+    }
+
+    fn resolve_ram(&self, name: &str) -> Result<u16, String> {
+        let address = match &self.info {
+            Some(info) => info.resolve_ram(name),
+            None => name.parse::<u16>().ok(),
+        };
+        address.filter(|&address| (address as usize) < crate::cpu::RAM_SIZE).ok_or_else(|| format!("unknown RAM address `{name}`"))
+    }
+
+    fn resolve_rom(&self, name: &str) -> Result<u16, String> {
+        let address = match &self.info {
+            Some(info) => info.resolve_rom(name),
+            None => name.parse::<u16>().ok(),
+        };
+        address.ok_or_else(|| format!("unknown label or ROM address `{name}`"))
+    }
+
+    pub fn parse_condition(&self, text: &str) -> Result<Condition, String> {
+        // longest operators first so `<=` isn't read as `<`
+        for operator in [">=", "<=", "==", "!=", "<>", ">", "<", "="] {
+            if let Some((left, right)) = text.split_once(operator) {
+                let comparison = Comparison::parse(operator).unwrap();
+                return Ok(Condition { left: self.parse_operand(left)?, comparison, right: self.parse_operand(right)? });
+            }
+        }
+        Err(format!("`{text}` is not a comparison"))
+    }
+
+    // `LABEL`, `123`, or either followed by `if <condition>`; returns the new breakpoint's id
+    pub fn add_breakpoint(&mut self, spec: &str) -> Result<usize, String> {
+        let (location, condition) = match spec.split_once(" if ") {
+            Some((location, condition)) => (location.trim(), Some(self.parse_condition(condition)?)),
+            None => (spec.trim(), None),
+        };
+        let address = self.resolve_rom(location)?;
+        if address as usize >= self.cpu.rom().len() {
+            return Err(format!("ROM[{address}] is past the end of the program"));
+        }
+        self.breakpoints.push(Some(Breakpoint { address, condition, description: spec.trim().to_string() }));
+        Ok(self.breakpoints.len())
+    }
+
+    pub fn delete_breakpoint(&mut self, id: usize) -> Result<(), String> {
+        match self.breakpoints.get_mut(id.wrapping_sub(1)) {
+            Some(breakpoint @ Some(_)) => {
+                *breakpoint = None;
+                Ok(())
+            },
+            _ => Err(format!("no breakpoint number {id}")),
+        }
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints.iter().enumerate().filter_map(|(idx, breakpoint)| breakpoint.as_ref().map(|breakpoint| (idx + 1, breakpoint)))
+    }
+
+    // `SP`, `RAM[THIS]`, `256..300` or `RAM[256..300]`, optionally followed by `log` to report hits
+    // without stopping; returns the new watchpoint's id
+    pub fn add_watchpoint(&mut self, kind: WatchKind, spec: &str) -> Result<usize, String> {
+        let (location, action) = match spec.trim().strip_suffix(" log") {
+            Some(location) => (location.trim(), WatchAction::Log),
+            None => (spec.trim(), WatchAction::Break),
+        };
+        if location.is_empty() {
+            return Err("expected a RAM address or range".to_string());
+        }
+        let range = location.strip_prefix("RAM[").and_then(|rest| rest.strip_suffix(']')).unwrap_or(location);
+        let (start, end) = match range.split_once("..") {
+            Some((start, end)) => (self.resolve_ram(start.trim())?, self.resolve_ram(end.trim())?),
+            None => {
+                let address = self.resolve_ram(range)?;
+                (address, address)
+            },
+        };
+        if start > end {
+            return Err(format!("`{location}` is an empty range"));
+        }
+        self.watchpoints.push(Some(Watchpoint { start, end, kind, action, description: spec.trim().to_string() }));
+        Ok(self.watchpoints.len())
+    }
+
+    pub fn delete_watchpoint(&mut self, id: usize) -> Result<(), String> {
+        match self.watchpoints.get_mut(id.wrapping_sub(1)) {
+            Some(watchpoint @ Some(_)) => {
+                *watchpoint = None;
+                Ok(())
+            },
+            _ => Err(format!("no watchpoint number {id}")),
+        }
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints.iter().enumerate().filter_map(|(idx, watchpoint)| watchpoint.as_ref().map(|watchpoint| (idx + 1, watchpoint)))
+    }
+
+    // the hits of `log` watchpoints since this was last called
+    pub fn take_logged(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.logged)
+    }
+
+    // the breakpoint that triggers at the current PC, if any
+    fn breakpoint_hit(&self) -> Option<usize> {
+        self.breakpoints()
+            .find(|(_, breakpoint)| breakpoint.address == self.cpu.pc && breakpoint.condition.is_none_or(|condition| condition.holds(&self.cpu)))
+            .map(|(id, _)| id)
+    }
+
+    // runs one instruction, keeping what it takes to undo it
+    fn forward(&mut self) -> Result<MemoryAccess, EmulatorError> {
+        self.history.checkpoint(&self.cpu);
+        let (a, d, cycles, key) = (self.cpu.a, self.cpu.d, self.cpu.cycles, self.cpu.key());
+        self.keys.apply(&mut self.cpu);
+        let access = self.cpu.step_traced()?;
+        self.history.record(Delta { a, d, cycles, key, access });
+        Ok(access)
+    }
+
+    // the first `break` watchpoint the instruction that ran on `cycle` triggered, keeping the hits
+    // of `log` ones when `log` is set
+    fn watch(&mut self, access: &MemoryAccess, cycle: u64, log: bool) -> Option<WatchHit> {
+        let mut stop = None;
+        for (id, watchpoint) in self.watchpoints.iter().enumerate() {
+            let Some(watchpoint) = watchpoint else { continue };
+            for event in watchpoint.events(access) {
+                let hit = WatchHit { id: id + 1, cycle, pc: access.pc, event };
+                match watchpoint.action {
+                    WatchAction::Log if log => self.logged.push(hit),
+                    WatchAction::Log => {},
+                    WatchAction::Break => { stop.get_or_insert(hit); },
+                }
+            }
+        }
+        stop
+    }
+
+    // runs one instruction, returning the first `break` watchpoint it triggered
+    pub fn step(&mut self) -> Result<Option<WatchHit>, EmulatorError> {
+        let access = self.forward()?;
+        Ok(self.watch(&access, self.cpu.cycles, true))
+    }
+
+    // undoes the last instruction, returning what it had done; when that's further back than the
+    // recorded deltas go, it replays from the last checkpoint before it to get them back
+    pub fn reverse_step(&mut self) -> Option<Delta> {
+        if !self.history.has_deltas() {
+            let target = self.cpu.cycles;
+            let checkpoint = self.history.checkpoint_before(target)?.clone();
+            checkpoint.restore(&mut self.cpu).ok()?;
+            self.keys.rewind(self.cpu.cycles);
+            while self.cpu.cycles < target {
+                // it already ran fine once, and runs the same way again
+                self.forward().ok()?;
+            }
+        }
+        let delta = self.history.undo(&mut self.cpu)?;
+        self.keys.rewind(self.cpu.cycles);
+        Some(delta)
+    }
+
+    // goes back until it's right before an instruction that triggers a breakpoint or a `break` watchpoint
+    pub fn reverse_cont(&mut self) -> Stop {
+        loop {
+            let Some(delta) = self.reverse_step() else { return Stop::NoHistory };
+            if let Some(hit) = self.watch(&delta.access, delta.cycles + 1, false) {
+                return Stop::Watchpoint(hit);
+            }
+            if let Some(id) = self.breakpoint_hit() {
+                return Stop::Breakpoint(id);
+            }
+        }
+    }
+
+    // keeps stepping until `done` says so, a breakpoint or watchpoint triggers or the program halts
+    pub fn run_until(&mut self, mut done: impl FnMut(&Cpu) -> bool) -> Stop {
+        loop {
+            if self.cpu.is_halted() {
+                return Stop::Halted;
+            }
+            match self.step() {
+                Ok(Some(hit)) => return Stop::Watchpoint(hit),
+                Ok(None) => {},
+                Err(error) => return Stop::Error(error),
+            }
+            if let Some(id) = self.breakpoint_hit() {
+                return Stop::Breakpoint(id);
+            }
+            if done(&self.cpu) {
+                return Stop::Done;
+            }
+        }
+    }
+
+    pub fn cont(&mut self) -> Stop {
+        self.run_until(|_| false)
+    }
+
+    // like `cont`, but stops with `Done` once `max_cycles` have run, so the caller gets a say every so often
+    pub fn cont_for(&mut self, max_cycles: u64) -> Stop {
+        let end = self.cpu.cycles + max_cycles;
+        self.run_until(|cpu| cpu.cycles >= end)
+    }
+
+    // runs over an unconditional jump until the instruction right after it, which is how
+    // calls look in generated code (`@f 0;JMP (RETURN)`); anything else is a single step
+    pub fn step_over(&mut self) -> Stop {
+        let pc = self.cpu.pc;
+        let word = self.cpu.rom().get(pc as usize).copied().unwrap_or(0);
+        match Instruction::decode(word) {
+            Some(Instruction::C(c_instr)) if c_instr.jump_if == JumpIf::Jmp => self.run_until(|cpu| cpu.pc == pc.wrapping_add(1)),
+            _ => self.run_until(|_| true),
+        }
+    }
+
+    fn ram_label(&self, address: u16) -> String {
+        watch::ram_label(self.info.as_ref(), address)
+    }
+
+    // `ROM[12] LOOP+2: D=M    Max.asm:9: D=M // load`
+    pub fn describe_instruction(&self, address: u16) -> String {
+        debug_info::describe_instruction(self.cpu.rom(), self.info.as_ref(), address)
+    }
+
+    fn describe_stop(&self, stop: Stop) -> String {
+        let location = self.describe_instruction(self.cpu.pc);
+        match stop {
+            Stop::Breakpoint(id) => format!("breakpoint {id} hit at {location}"),
+            Stop::Watchpoint(hit) => format!("{}\nnow at {location}", hit.describe(self.info.as_ref())),
+            Stop::Halted => format!("program halted after {} cycles at {location}", self.cpu.cycles),
+            Stop::Done => location,
+            Stop::NoHistory => format!("no history before this, at {location}"),
+            Stop::Error(error) => format!("error: {error}"),
+        }
+    }
+
+    // prints what `log` watchpoints saw on the way, then where and why it stopped
+    fn report(&mut self, stop: Stop, out: &mut impl Write) -> io::Result<()> {
+        for hit in self.take_logged() {
+            writeln!(out, "{}", hit.describe(self.info.as_ref()))?;
+        }
+        writeln!(out, "{}", self.describe_stop(stop))
+    }
+
+    fn print(&self, what: &str, out: &mut impl Write) -> Result<(), String> {
+        let what = what.trim();
+        if let Some(range) = what.strip_prefix("RAM[").and_then(|rest| rest.strip_suffix(']')) {
+            if let Some((start, end)) = range.split_once("..") {
+                let (start, end) = (self.resolve_ram(start)?, self.resolve_ram(end)?);
+                for address in start..=end {
+                    writeln!(out, "{} = {}", self.ram_label(address), self.cpu.read(address) as i16).map_err(|error| error.to_string())?;
+                }
+                return Ok(());
+            }
+        }
+        let text = match self.parse_operand(what) {
+            Ok(Operand::Ram(address)) => format!("{} = {}", self.ram_label(address), self.cpu.read(address) as i16),
+            Ok(operand @ (Operand::A | Operand::D | Operand::PC)) => format!("{what} = {}", operand.get(&self.cpu)),
+            Ok(Operand::Const(value)) => value.to_string(),
+            // labels print their ROM address
+            Err(error) => match self.resolve_rom(what) {
+                Ok(address) => format!("{what} = ROM[{address}]"),
+                Err(_) => return Err(error),
+            },
+        };
+        writeln!(out, "{text}").map_err(|error| error.to_string())
+    }
+
+    // runs one command, returning false when the user wants to quit
+    pub fn execute(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        // an empty line repeats the last command, like gdb does
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+        let (command, args) = line.split_once(' ').map_or((line.as_str(), ""), |(command, args)| (command, args.trim()));
+
+        let result: Result<(), String> = match command {
+            "" => Ok(()),
+            "break" | "b" => self.add_breakpoint(args).map(|id| {
+                let address = self.breakpoints[id - 1].as_ref().unwrap().address;
+                writeln!(out, "breakpoint {id} at {}", self.describe_instruction(address)).ok();
+            }),
+            "delete" | "d" => args.parse::<usize>().map_err(|_| format!("`{args}` is not a breakpoint number")).and_then(|id| self.delete_breakpoint(id)),
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match command {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                self.add_watchpoint(kind, args).map(|id| {
+                    let watchpoint = self.watchpoints[id - 1].as_ref().unwrap();
+                    let (start, end) = (watchpoint.start, watchpoint.end);
+                    let range = if start == end { self.ram_label(start) } else { format!("{}..{}", self.ram_label(start), self.ram_label(end)) };
+                    writeln!(out, "watchpoint {id} on {range}").ok();
+                })
+            },
+            "unwatch" => args.parse::<usize>().map_err(|_| format!("`{args}` is not a watchpoint number")).and_then(|id| self.delete_watchpoint(id)),
+            "info" if args == "breakpoints" || args == "b" => {
+                for (id, breakpoint) in self.breakpoints() {
+                    writeln!(out, "{id}: {} (ROM[{}])", breakpoint.description, breakpoint.address)?;
+                }
+                Ok(())
+            },
+            "info" if args == "watchpoints" || args == "w" => {
+                for (id, watchpoint) in self.watchpoints() {
+                    let kind = match watchpoint.kind {
+                        WatchKind::Read => "read",
+                        WatchKind::Write => "write",
+                        WatchKind::Access => "access",
+                    };
+                    writeln!(out, "{id}: {kind} {} (RAM[{}..{}])", watchpoint.description, watchpoint.start, watchpoint.end)?;
+                }
+                Ok(())
+            },
+            "info" | "regs" if command == "regs" || args == "registers" || args == "r" => {
+                writeln!(out, "A={} D={} PC={} cycles={}", self.cpu.a as i16, self.cpu.d as i16, self.cpu.pc, self.cpu.cycles)?;
+                Ok(())
+            },
+            "step" | "s" => {
+                let count = if args.is_empty() { Ok(1) } else { args.parse::<u64>().map_err(|_| format!("`{args}` is not a step count")) };
+                count.map(|count| {
+                    let mut left = count.max(1);
+                    let stop = self.run_until(|_| { left -= 1; left == 0 });
+                    self.report(stop, out).ok();
+                })
+            },
+            "next" | "n" => {
+                let stop = self.step_over();
+                self.report(stop, out)?;
+                Ok(())
+            },
+            "continue" | "c" => {
+                let stop = self.cont();
+                self.report(stop, out)?;
+                Ok(())
+            },
+            "reverse-step" | "rs" => {
+                let count = if args.is_empty() { Ok(1) } else { args.parse::<u64>().map_err(|_| format!("`{args}` is not a step count")) };
+                count.map(|count| {
+                    let count = count.max(1);
+                    let undone = (0..count).take_while(|_| self.reverse_step().is_some()).count() as u64;
+                    let stop = if undone < count { Stop::NoHistory } else { Stop::Done };
+                    self.report(stop, out).ok();
+                })
+            },
+            "reverse-continue" | "rc" => {
+                let stop = self.reverse_cont();
+                self.report(stop, out)?;
+                Ok(())
+            },
+            "print" | "p" => self.print(args, out),
+            "snapshot" => match args.split_once(' ') {
+                Some(("save", path)) => Snapshot::of(&self.cpu).save(path.trim()).map_err(|error| error.to_string()),
+                Some(("load", path)) => Snapshot::load(path.trim()).and_then(|snapshot| snapshot.restore(&mut self.cpu)).map_err(|error| error.to_string()).map(|_| {
+                    // the way here isn't known, so there's nothing to go back to
+                    self.history.clear();
+                    self.keys.rewind(self.cpu.cycles);
+                    writeln!(out, "{}", self.describe_instruction(self.cpu.pc)).ok();
+                }),
+                _ => Err("expected `snapshot save <file>` or `snapshot load <file>`".to_string()),
+            },
+            "list" | "l" => {
+                let count = args.parse::<u16>().unwrap_or(5);
+                let start = self.cpu.pc.saturating_sub(count / 2);
+                for address in start..(start + count).min(self.cpu.rom().len() as u16) {
+                    let marker = if address == self.cpu.pc { "=>" } else { "  " };
+                    writeln!(out, "{marker} {}", self.describe_instruction(address))?;
+                }
+                Ok(())
+            },
+            "quit" | "q" => return Ok(false),
+            "help" | "h" => {
+                writeln!(out, "break|b <label|address> [if <operand> <op> <operand>]   set a breakpoint")?;
+                writeln!(out, "delete|d <id>                 delete a breakpoint")?;
+                writeln!(out, "watch|rwatch|awatch <x|x..y> [log]   stop (or just log) when RAM is written, read, or either")?;
+                writeln!(out, "unwatch <id>                  delete a watchpoint")?;
+                writeln!(out, "info breakpoints|watchpoints|registers")?;
+                writeln!(out, "step|s [n]                    run n instructions (1 by default)")?;
+                writeln!(out, "next|n                        step, running over jumps until the next instruction")?;
+                writeln!(out, "continue|c                    run until a breakpoint or the program halts")?;
+                writeln!(out, "reverse-step|rs [n]           undo n instructions (1 by default)")?;
+                writeln!(out, "reverse-continue|rc           go back to the last breakpoint or watchpoint hit")?;
+                writeln!(out, "print|p <A|D|PC|RAM[x]|RAM[x..y]|symbol>")?;
+                writeln!(out, "list|l [n]                    show n instructions around PC")?;
+                writeln!(out, "snapshot save|load <file>     save the machine state, or go back to a saved one")?;
+                writeln!(out, "quit|q")?;
+                Ok(())
+            },
+            _ => Err(format!("unknown command `{command}`, try `help`")),
+        };
+        if let Err(error) = result {
+            writeln!(out, "error: {error}")?;
+        }
+        Ok(true)
+    }
+
+    pub fn run(&mut self, input: &mut impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", self.describe_instruction(self.cpu.pc))?;
+        loop {
+            write!(out, "(hdb) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 || !self.execute(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+}
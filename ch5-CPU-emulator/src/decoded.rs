@@ -0,0 +1,46 @@
+// the ROM decoded once up front and cut into basic blocks, so running a program doesn't decode
+// a word per cycle nor check for the end of the program after every instruction
+use assembler::instruction::{CInstr, Comp, Instruction, JumpIf, SaveCompTo};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedRom {
+    // None for words that aren't valid instructions
+    instructions: Vec<Option<Instruction>>,
+    // per ROM address, the last address of the block it's in: blocks end with a jump, at the end
+    // of the ROM, or right before a `(END) @END 0;JMP` loop, so halting is only checked between blocks
+    block_ends: Vec<u16>,
+}
+
+// the `0;JMP` of the loop programs end with, see `Cpu::is_halted`
+pub fn end_loop_jump() -> u16 {
+    Instruction::C(CInstr { comp: Comp::Zero, save_comp_to: SaveCompTo::default(), jump_if: JumpIf::Jmp, switch_a_for_m: false }).encode()
+}
+
+impl DecodedRom {
+    pub fn new(rom: &[u16]) -> Self {
+        let instructions = rom.iter().map(|&word| Instruction::decode(word)).collect::<Vec<_>>();
+        let halts_at = |address: usize| rom.get(address) == Some(&(address as u16)) && rom.get(address + 1) == Some(&end_loop_jump());
+        let mut block_ends = vec![0; rom.len()];
+        for address in (0..rom.len()).rev() {
+            let jumps = matches!(instructions[address], Some(Instruction::C(c_instr)) if c_instr.jump_if != JumpIf::Null);
+            block_ends[address] = if jumps || address + 1 == rom.len() || halts_at(address + 1) {
+                address as u16
+            } else {
+                block_ends[address + 1]
+            };
+        }
+        Self { instructions, block_ends }
+    }
+
+    pub fn get(&self, address: u16) -> Option<Instruction> {
+        self.instructions.get(address as usize).copied().flatten()
+    }
+
+    // the instructions from this address to the end of its block, empty past the end of the ROM
+    pub fn block(&self, address: u16) -> &[Option<Instruction>] {
+        match self.block_ends.get(address as usize) {
+            Some(&end) => &self.instructions[address as usize..=end as usize],
+            None => &[],
+        }
+    }
+}
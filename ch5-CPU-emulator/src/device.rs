@@ -0,0 +1,398 @@
+// memory-mapped devices besides SCREEN and KBD, attached to the CPU at an address of our choosing,
+// plus a few ready-made ones: a serial console, a timer, a random number generator and an exit code port
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+pub trait Device {
+    fn name(&self) -> &str;
+
+    // how many words of the address space it takes, starting where it's attached
+    fn size(&self) -> u16;
+
+    // what the program reads at `offset` words into the device, which may have side effects like
+    // taking the next input byte; `cycles` is the instruction's cycle
+    fn read(&mut self, offset: u16, cycles: u64) -> u16;
+
+    // what `read` would give, without the side effects, for debuggers and dumps
+    fn peek(&self, offset: u16, cycles: u64) -> u16;
+
+    fn write(&mut self, offset: u16, value: u16, cycles: u64);
+
+    // set once the program told the host it's done, with the code to exit with
+    fn exit_code(&self) -> Option<u16> {
+        None
+    }
+
+    // back to how it was when attached, for `Cpu::reset`
+    fn reset(&mut self) {}
+
+    // its state, for snapshots, and putting back a state `save` gave
+    fn save(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        match state {
+            [] => Ok(()),
+            _ => Err(format!("`{}` has no state to restore", self.name())),
+        }
+    }
+}
+
+fn bad_state(device: &dyn Device) -> String {
+    format!("not a `{}` state", device.name())
+}
+
+// word 0: writing sends the low byte to the host's output, reading takes the next input byte,
+// waiting for the host to send one, or 0 once the input is closed; word 1: reads 1 when a byte has
+// arrived, without waiting. The host's input is only read from once the program reads the device,
+// so it works interactively and doesn't take input meant for anything else before that
+pub struct Serial {
+    // what was taken from the host so far, replayed after a reset
+    input: Vec<u8>,
+    // the next byte of `input` the program gets
+    position: usize,
+    source: Source,
+    output: Box<dyn Write>,
+}
+
+// where more input comes from
+enum Source {
+    // until the program first reads the device
+    Unread(Box<dyn Read + Send>),
+    // a thread reads it, handing over bytes as they arrive
+    Reading(Receiver<u8>),
+    // closed, or broken, which the program can't do anything about either
+    Closed,
+}
+
+impl Serial {
+    pub fn new(source: Box<dyn Read + Send>, output: Box<dyn Write>) -> Self {
+        Self { input: vec![], position: 0, source: Source::Unread(source), output }
+    }
+
+    // takes the bytes that arrived so far, waiting for one if `wait` and there's none left; returns
+    // whether there's a byte left for the program
+    fn receive(&mut self, wait: bool) -> bool {
+        if let Source::Unread(_) = self.source {
+            let Source::Unread(mut source) = mem::replace(&mut self.source, Source::Closed) else { unreachable!() };
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let mut byte = [0];
+                while source.read_exact(&mut byte).is_ok() && sender.send(byte[0]).is_ok() {}
+            });
+            self.source = Source::Reading(receiver);
+        }
+        let Source::Reading(receiver) = &self.source else {
+            return self.position < self.input.len();
+        };
+        let mut closed = false;
+        loop {
+            let byte = match wait && self.position == self.input.len() {
+                true => receiver.recv().map_err(|_| TryRecvError::Disconnected),
+                false => receiver.try_recv(),
+            };
+            match byte {
+                Ok(byte) => self.input.push(byte),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    closed = true;
+                    break;
+                },
+            }
+        }
+        if closed {
+            self.source = Source::Closed;
+        }
+        self.position < self.input.len()
+    }
+}
+
+impl Device for Serial {
+    fn name(&self) -> &str {
+        "serial"
+    }
+
+    fn size(&self) -> u16 {
+        2
+    }
+
+    fn read(&mut self, offset: u16, _cycles: u64) -> u16 {
+        let available = self.receive(offset == 0);
+        match offset {
+            0 if available => {
+                self.position += 1;
+                self.input[self.position - 1] as u16
+            },
+            0 => 0,
+            _ => available as u16,
+        }
+    }
+
+    // only knows about the input already taken from the host, asking for more could wait
+    fn peek(&self, offset: u16, _cycles: u64) -> u16 {
+        match offset {
+            0 => self.input.get(self.position).copied().unwrap_or(0) as u16,
+            _ => (self.position < self.input.len()) as u16,
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u16, _cycles: u64) {
+        if offset == 0 {
+            // the program has no way to hear about it, so a broken pipe just loses the output
+            let _ = self.output.write_all(&[value as u8]).and_then(|_| self.output.flush());
+        }
+    }
+
+    fn reset(&mut self) {
+        self.position = 0;
+    }
+
+    // the input taken so far and how much of it the program read; what the host sends next isn't
+    // part of it
+    fn save(&self) -> Vec<u8> {
+        [&(self.position as u64).to_le_bytes()[..], &self.input].concat()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let (position, input) = state.split_first_chunk::<8>().ok_or_else(|| bad_state(self))?;
+        let position = u64::from_le_bytes(*position) as usize;
+        if position > input.len() {
+            return Err(bad_state(self));
+        }
+        (self.input, self.position) = (input.to_vec(), position);
+        Ok(())
+    }
+}
+
+// reads the ticks since it was last written to, a tick being `period` cycles; it wraps around at 65536
+pub struct Timer {
+    period: u64,
+    start: u64,
+}
+
+impl Timer {
+    pub fn new(period: u64) -> Self {
+        Self { period: period.max(1), start: 0 }
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "timer"
+    }
+
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, offset: u16, cycles: u64) -> u16 {
+        self.peek(offset, cycles)
+    }
+
+    fn peek(&self, _offset: u16, cycles: u64) -> u16 {
+        (cycles.saturating_sub(self.start) / self.period) as u16
+    }
+
+    fn write(&mut self, _offset: u16, _value: u16, cycles: u64) {
+        self.start = cycles;
+    }
+
+    fn reset(&mut self) {
+        self.start = 0;
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.start.to_le_bytes().to_vec()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        self.start = u64::from_le_bytes(state.try_into().map_err(|_| bad_state(self))?);
+        Ok(())
+    }
+}
+
+// every read gives the next number of a xorshift sequence, writing sets the seed
+pub struct Rng {
+    seed: u32,
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u16) -> Self {
+        let state = Self::state_for(seed);
+        Self { seed: state, state }
+    }
+
+    // xorshift gets stuck on 0
+    fn state_for(seed: u16) -> u32 {
+        (seed as u32) << 16 | 0x9e37
+    }
+
+    fn next(state: u32) -> u32 {
+        let mut state = state;
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^ state << 5
+    }
+}
+
+impl Device for Rng {
+    fn name(&self) -> &str {
+        "rng"
+    }
+
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, offset: u16, cycles: u64) -> u16 {
+        let value = self.peek(offset, cycles);
+        self.state = Self::next(self.state);
+        value
+    }
+
+    fn peek(&self, _offset: u16, _cycles: u64) -> u16 {
+        // the high bits are the better ones
+        (Self::next(self.state) >> 16) as u16
+    }
+
+    fn write(&mut self, _offset: u16, value: u16, _cycles: u64) {
+        self.state = Self::state_for(value);
+    }
+
+    fn reset(&mut self) {
+        self.state = self.seed;
+    }
+
+    // the seed too, so a reset after restoring goes back to the same sequence
+    fn save(&self) -> Vec<u8> {
+        [self.seed.to_le_bytes(), self.state.to_le_bytes()].concat()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let words = <[u8; 8]>::try_from(state).map_err(|_| bad_state(self))?;
+        self.seed = u32::from_le_bytes(words[..4].try_into().unwrap());
+        self.state = u32::from_le_bytes(words[4..].try_into().unwrap());
+        Ok(())
+    }
+}
+
+// writing a value stops the program, with that value as its exit code (0 for pass, anything else
+// for fail, by the usual convention)
+#[derive(Default)]
+pub struct ExitPort {
+    code: Option<u16>,
+}
+
+impl Device for ExitPort {
+    fn name(&self) -> &str {
+        "exit"
+    }
+
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, offset: u16, cycles: u64) -> u16 {
+        self.peek(offset, cycles)
+    }
+
+    fn peek(&self, _offset: u16, _cycles: u64) -> u16 {
+        0
+    }
+
+    fn write(&mut self, _offset: u16, value: u16, _cycles: u64) {
+        self.code = Some(value);
+    }
+
+    fn exit_code(&self) -> Option<u16> {
+        self.code
+    }
+
+    fn reset(&mut self) {
+        self.code = None;
+    }
+
+    // empty while there's no code
+    fn save(&self) -> Vec<u8> {
+        self.code.map_or(vec![], |code| code.to_le_bytes().to_vec())
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        self.code = match state {
+            [] => None,
+            &[low, high] => Some(u16::from_le_bytes([low, high])),
+            _ => return Err(bad_state(self)),
+        };
+        Ok(())
+    }
+}
+
+// `serial@24577`, `timer@24579`, `timer@24579:1000` (cycles per tick), `rng@24580`, `rng@24580:42`
+// (the seed) or `exit@24581`; the serial console talks to stdin and stdout
+pub fn parse_device(spec: &str) -> Result<(u16, Box<dyn Device>), String> {
+    let (kind, rest) = spec.split_once('@').ok_or_else(|| format!("`{spec}` should look like `<kind>@<address>`"))?;
+    let (address, arg) = match rest.split_once(':') {
+        Some((address, arg)) => (address, Some(arg)),
+        None => (rest, None),
+    };
+    let address = address.parse::<u16>().map_err(|_| format!("`{address}` is not an address"))?;
+    let number = |default: u64| arg.map_or(Ok(default), |arg| arg.parse::<u64>().map_err(|_| format!("`{arg}` is not a number")));
+    if arg.is_some() && (kind == "serial" || kind == "exit") {
+        return Err(format!("`{kind}` doesn't take an argument"));
+    }
+    let device: Box<dyn Device> = match kind {
+        "serial" => Box::new(Serial::new(Box::new(io::stdin()), Box::new(io::stdout()))),
+        "timer" => Box::new(Timer::new(number(1000)?)),
+        "rng" => {
+            let seed = number(1)?;
+            Box::new(Rng::new(u16::try_from(seed).map_err(|_| format!("the rng seed `{seed}` is past {}", u16::MAX))?))
+        },
+        "exit" => Box::new(ExitPort::default()),
+        _ => return Err(format!("unknown device `{kind}`, expected serial, timer, rng or exit")),
+    };
+    Ok((address, device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // input that never comes, like a terminal no one types in
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                thread::park();
+            }
+        }
+    }
+
+    #[test]
+    fn serial_status_doesnt_wait_for_input() {
+        let mut serial = Serial::new(Box::new(Silent), Box::new(io::sink()));
+        assert_eq!(serial.read(1, 0), 0);
+        assert_eq!(serial.read(1, 1), 0);
+    }
+
+    #[test]
+    fn serial_reads_wait_for_input_until_its_closed() {
+        let mut serial = Serial::new(Box::new(&b"hi"[..]), Box::new(io::sink()));
+        assert_eq!(serial.read(0, 0), b'h' as u16);
+        assert_eq!(serial.read(0, 1), b'i' as u16);
+        assert_eq!(serial.read(0, 2), 0);
+        assert_eq!(serial.read(1, 3), 0);
+        serial.reset();
+        assert_eq!(serial.read(1, 4), 1);
+    }
+
+    #[test]
+    fn rng_seeds_are_16_bits() {
+        assert!(parse_device("rng@24577:65535").is_ok());
+        assert!(matches!(parse_device("rng@24577:65536"), Err(error) if error.contains("65536")));
+    }
+}
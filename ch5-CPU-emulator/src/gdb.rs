@@ -0,0 +1,340 @@
+// the GDB remote serial protocol on top of the debugger, so gdb/lldb and IDEs can drive the emulator
+//
+// registers are A, D and PC, 16 bits each. Memory is byte addressed and little endian: RAM[n] is at
+// 2n (so the RAM takes 0x0000-0xffff), and ROM[n] at 0x10000 + 2n, read only. Breakpoints take ROM
+// addresses like PC does, watchpoints take byte addresses into the RAM
+use std::io::{self, prelude::*, BufReader, ErrorKind};
+use std::net::TcpStream;
+
+use crate::cpu::RAM_SIZE;
+use crate::debugger::{Debugger, Stop};
+use crate::watch::{WatchEvent, WatchKind};
+
+const ROM_BASE: u32 = 0x10000;
+// how many cycles `continue` runs between checks for an interrupt from gdb
+const CYCLES_PER_POLL: u64 = 100_000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nand2tetris.hack">
+    <reg name="a" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="d" bitsize="16" type="int16" regnum="1"/>
+    <reg name="pc" bitsize="16" type="code_ptr" regnum="2"/>
+  </feature>
+</target>
+"#;
+
+pub struct GdbStub {
+    pub debugger: Debugger,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    // after `QStartNoAckMode`, packets aren't acknowledged anymore
+    no_ack: bool,
+    // the reply to `?`
+    last_stop: String,
+    // (Z packet type, address, length) -> debugger breakpoint/watchpoint id
+    points: Vec<((u8, u32, u32), usize)>,
+}
+
+fn hex_word(word: u16) -> String {
+    word.to_le_bytes().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+// a little endian hex word, like `hex_word` writes
+fn parse_hex_word(text: &str) -> Option<u16> {
+    let low = u8::from_str_radix(text.get(0..2)?, 16).ok()?;
+    let high = u8::from_str_radix(text.get(2..4)?, 16).ok()?;
+    (text.len() == 4).then_some(u16::from_le_bytes([low, high]))
+}
+
+impl GdbStub {
+    pub fn new(debugger: Debugger, stream: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            debugger,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            no_ack: false,
+            last_stop: "S05".to_string(),
+            points: vec![],
+        })
+    }
+
+    // the next packet's contents, or None once gdb hangs up; a lone ^C comes back as "\x03"
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut byte = [0u8];
+            if self.reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'$' => {
+                    let mut data = vec![];
+                    self.reader.read_until(b'#', &mut data)?;
+                    data.pop();
+                    let mut checksum = [0u8; 2];
+                    self.reader.read_exact(&mut checksum)?;
+                    if !self.no_ack {
+                        let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                        let actual = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+                        if expected != Some(actual) {
+                            self.writer.write_all(b"-")?;
+                            continue;
+                        }
+                        self.writer.write_all(b"+")?;
+                    }
+                    return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+                },
+                0x03 => return Ok(Some("\x03".to_string())),
+                // acks for our replies, and anything else between packets
+                _ => {},
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.writer, "${data}#{checksum:02x}")?;
+        self.writer.flush()
+    }
+
+    // whether gdb sent a ^C (or hung up) while the program was running, without waiting for it
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if self.reader.buffer().is_empty() {
+            self.reader.get_ref().set_nonblocking(true)?;
+            let result = self.reader.fill_buf().map(|buffer| buffer.is_empty());
+            self.reader.get_ref().set_nonblocking(false)?;
+            match result {
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(error) => return Err(error),
+                Ok(hung_up) if hung_up => return Ok(true),
+                Ok(_) => {},
+            }
+        }
+        if self.reader.buffer()[0] == 0x03 {
+            self.reader.consume(1);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Breakpoint(_) | Stop::Done => "S05".to_string(),
+            Stop::Watchpoint(hit) => {
+                let kind = self.debugger.watchpoints().find(|(id, _)| *id == hit.id).map_or(WatchKind::Write, |(_, watchpoint)| watchpoint.kind);
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                let address = match hit.event {
+                    WatchEvent::Read { address, .. } => address,
+                    WatchEvent::Write(write) => write.address,
+                };
+                format!("T05{name}:{:x};", address as u32 * 2)
+            },
+            Stop::Halted => "W00".to_string(),
+            Stop::NoHistory => "T05replaylog:begin;".to_string(),
+            // SIGILL
+            Stop::Error(_) => "S04".to_string(),
+        }
+    }
+
+    fn read_byte(&self, address: u32) -> Option<u8> {
+        let word = if address < ROM_BASE {
+            // through `read` like the debugger, so mapped devices show what the program would read
+            ((address as usize) < RAM_SIZE * 2).then(|| self.debugger.cpu.read((address / 2) as u16))
+        } else {
+            self.debugger.cpu.rom().get((address - ROM_BASE) as usize / 2).copied()
+        }?;
+        Some(word.to_le_bytes()[address as usize % 2])
+    }
+
+    fn write_byte(&mut self, address: u32, byte: u8) -> Option<()> {
+        if address as usize >= RAM_SIZE * 2 {
+            return None;
+        }
+        let word_address = (address / 2) as u16;
+        let mut bytes = self.debugger.cpu.read(word_address).to_le_bytes();
+        bytes[address as usize % 2] = byte;
+        self.debugger.cpu.write(word_address, u16::from_le_bytes(bytes));
+        Some(())
+    }
+
+    // `Z<type>,<address>,<kind>` and `z...`; types 0 and 1 are breakpoints, 2/3/4 write/read/access watchpoints
+    fn set_point(&mut self, packet: &str) -> Option<String> {
+        let insert = packet.starts_with('Z');
+        let mut fields = packet[1..].split(',');
+        let point_type = fields.next()?.parse::<u8>().ok()?;
+        let address = parse_hex(fields.next()?)?;
+        let length = parse_hex(fields.next()?.split(';').next()?)?;
+        let key = (point_type, address, length);
+        let existing = self.points.iter().position(|(point, _)| *point == key);
+        if !insert {
+            let Some(idx) = existing else { return Some("OK".to_string()) };
+            let (_, id) = self.points.remove(idx);
+            let result = if point_type <= 1 { self.debugger.delete_breakpoint(id) } else { self.debugger.delete_watchpoint(id) };
+            return Some(if result.is_ok() { "OK" } else { "E01" }.to_string());
+        }
+        if existing.is_some() {
+            return Some("OK".to_string());
+        }
+        let id = match point_type {
+            0 | 1 => self.debugger.add_breakpoint(&address.to_string()),
+            2..=4 => {
+                let kind = [WatchKind::Write, WatchKind::Read, WatchKind::Access][point_type as usize - 2];
+                let (start, end) = (address / 2, address.checked_add(length.max(1) - 1)? / 2);
+                self.debugger.add_watchpoint(kind, &format!("{start}..{end}"))
+            },
+            _ => return Some(String::new()),
+        };
+        Some(match id {
+            Ok(id) => {
+                self.points.push((key, id));
+                "OK".to_string()
+            },
+            Err(_) => "E01".to_string(),
+        })
+    }
+
+    // the reply to a packet, or None when the session is over
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let cpu = &mut self.debugger.cpu;
+        let reply = match packet.as_bytes().first() {
+            None => String::new(),
+            Some(b'\x03') | Some(b'?') => self.last_stop.clone(),
+            Some(b'q') => self.query(packet),
+            // this packet was already acknowledged, the reply is the first one that won't be
+            Some(b'Q') if packet == "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            },
+            Some(b'g') => [cpu.a, cpu.d, cpu.pc].iter().map(|&word| hex_word(word)).collect(),
+            Some(b'G') => {
+                let words = (0..3).map(|idx| packet.get(1 + idx * 4..5 + idx * 4).and_then(parse_hex_word)).collect::<Option<Vec<_>>>();
+                match words.as_deref() {
+                    Some(&[a, d, pc]) => {
+                        (cpu.a, cpu.d, cpu.pc) = (a, d, pc);
+                        // the recorded history doesn't lead here anymore
+                        self.debugger.history.clear();
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            Some(b'p') => match parse_hex(&packet[1..]) {
+                Some(0) => hex_word(cpu.a),
+                Some(1) => hex_word(cpu.d),
+                Some(2) => hex_word(cpu.pc),
+                _ => "E01".to_string(),
+            },
+            Some(b'P') => {
+                let register = packet[1..].split_once('=').and_then(|(register, value)| Some((parse_hex(register)?, parse_hex_word(value)?)));
+                match register {
+                    Some((0, value)) => cpu.a = value,
+                    Some((1, value)) => cpu.d = value,
+                    Some((2, value)) => cpu.pc = value,
+                    _ => return Ok(Some("E01".to_string())),
+                }
+                self.debugger.history.clear();
+                "OK".to_string()
+            },
+            Some(b'm') => {
+                let range = packet[1..].split_once(',').and_then(|(address, length)| Some((parse_hex(address)?, parse_hex(length)?)));
+                let bytes = range.and_then(|(address, length)| (address..address.saturating_add(length)).map(|address| self.read_byte(address)).collect::<Option<Vec<_>>>());
+                bytes.map_or("E01".to_string(), |bytes| bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+            },
+            Some(b'M') => {
+                let parsed = packet[1..].split_once(':').and_then(|(range, data)| {
+                    let (address, length) = range.split_once(',')?;
+                    // checked up front so slicing it by byte index can't split a character
+                    if data.len() % 2 != 0 || !data.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                        return None;
+                    }
+                    let bytes = (0..data.len() / 2).map(|idx| u8::from_str_radix(&data[idx * 2..idx * 2 + 2], 16).ok()).collect::<Option<Vec<_>>>()?;
+                    (bytes.len() as u32 == parse_hex(length)?).then_some((parse_hex(address)?, bytes))
+                });
+                let written = parsed.and_then(|(address, bytes)| {
+                    bytes.iter().enumerate().try_for_each(|(idx, &byte)| self.write_byte(address.checked_add(idx as u32)?, byte))
+                });
+                self.debugger.history.clear();
+                if written.is_some() { "OK" } else { "E01" }.to_string()
+            },
+            Some(b'Z') | Some(b'z') => self.set_point(packet).unwrap_or_else(|| "E01".to_string()),
+            Some(b's') => {
+                let stop = match self.debugger.step() {
+                    Ok(Some(hit)) => Stop::Watchpoint(hit),
+                    Ok(None) if self.debugger.cpu.is_halted() => Stop::Halted,
+                    Ok(None) => Stop::Done,
+                    Err(error) => Stop::Error(error),
+                };
+                self.stop_reply(stop)
+            },
+            Some(b'c') => loop {
+                match self.debugger.cont_for(CYCLES_PER_POLL) {
+                    Stop::Done if self.interrupted()? => break "S02".to_string(),
+                    Stop::Done => {},
+                    stop => break self.stop_reply(stop),
+                }
+            },
+            Some(b'b') if packet == "bs" => match self.debugger.reverse_step() {
+                Some(_) => "S05".to_string(),
+                None => self.stop_reply(Stop::NoHistory),
+            },
+            Some(b'b') if packet == "bc" => {
+                let stop = self.debugger.reverse_cont();
+                self.stop_reply(stop)
+            },
+            Some(b'H') | Some(b'T') => "OK".to_string(),
+            Some(b'D') => {
+                self.send("OK")?;
+                return Ok(None);
+            },
+            Some(b'k') => return Ok(None),
+            // anything else isn't supported, which gdb takes as an empty reply
+            _ => String::new(),
+        };
+        if reply.starts_with('S') || reply.starts_with('T') || reply.starts_with('W') {
+            self.last_stop = reply.clone();
+        }
+        Ok(Some(reply))
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = range.split_once(',').and_then(|(offset, length)| Some((parse_hex(offset)? as usize, parse_hex(length)? as usize))) else {
+                return "E01".to_string();
+            };
+            let chunk = TARGET_XML.get(offset.min(TARGET_XML.len())..(offset + length).min(TARGET_XML.len())).unwrap_or("");
+            let more = offset + length < TARGET_XML.len();
+            return format!("{}{chunk}", if more { 'm' } else { 'l' });
+        }
+        match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            "qSymbol::" => "OK",
+            _ => "",
+        }.to_string()
+    }
+
+    // answers gdb until it detaches, kills the program or hangs up
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet)? {
+                Some(reply) => self.send(&reply)?,
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+// what the last instructions changed, so they can be undone one by one, plus snapshots every so often
+// to get further back than that by replaying from one of them
+use std::collections::VecDeque;
+
+use crate::cpu::MemoryAccess;
+use crate::snapshot::Snapshot;
+use crate::Cpu;
+
+// about 32 bytes each
+const MAX_DELTAS: usize = 1 << 20;
+// 64KB each, so 64 of them 100000 cycles apart reach 6.4M cycles back for 4MB
+const CHECKPOINT_INTERVAL: u64 = 100_000;
+const MAX_CHECKPOINTS: usize = 64;
+
+// the state before an instruction that it may have changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delta {
+    pub a: u16,
+    pub d: u16,
+    pub cycles: u64,
+    // the key held down before the keyboard script got a say
+    pub key: u16,
+    // what the instruction did, which has its PC and the old value of what it wrote
+    pub access: MemoryAccess,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    deltas: VecDeque<Delta>,
+    max_deltas: usize,
+    // oldest first
+    checkpoints: VecDeque<Snapshot>,
+    checkpoint_interval: u64,
+    max_checkpoints: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(MAX_DELTAS, CHECKPOINT_INTERVAL, MAX_CHECKPOINTS)
+    }
+}
+
+impl History {
+    pub fn new(max_deltas: usize, checkpoint_interval: u64, max_checkpoints: usize) -> Self {
+        Self { deltas: VecDeque::new(), max_deltas, checkpoints: VecDeque::new(), checkpoint_interval, max_checkpoints }
+    }
+
+    // call it before each instruction; takes a checkpoint when it's been long enough since the last one
+    pub fn checkpoint(&mut self, cpu: &Cpu) {
+        let due = self.checkpoints.back().is_none_or(|last| cpu.cycles >= last.cycles + self.checkpoint_interval);
+        if due {
+            if self.checkpoints.len() == self.max_checkpoints {
+                self.checkpoints.pop_front();
+            }
+            self.checkpoints.push_back(Snapshot::of(cpu));
+        }
+    }
+
+    pub fn record(&mut self, delta: Delta) {
+        if self.deltas.len() == self.max_deltas {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(delta);
+    }
+
+    // puts the CPU back to before the last recorded instruction, returning its delta
+    pub fn undo(&mut self, cpu: &mut Cpu) -> Option<Delta> {
+        let delta = self.deltas.pop_back()?;
+        cpu.a = delta.a;
+        cpu.d = delta.d;
+        cpu.pc = delta.access.pc;
+        cpu.cycles = delta.cycles;
+        cpu.set_key(delta.key);
+        if let Some(write) = delta.access.write {
+            cpu.restore_word(write.address, write.old);
+        }
+        Some(delta)
+    }
+
+    pub fn has_deltas(&self) -> bool {
+        !self.deltas.is_empty()
+    }
+
+    // the latest checkpoint from before this cycle, to replay from
+    pub fn checkpoint_before(&self, cycles: u64) -> Option<&Snapshot> {
+        self.checkpoints.iter().rev().find(|checkpoint| checkpoint.cycles < cycles)
+    }
+
+    pub fn clear_deltas(&mut self) {
+        self.deltas.clear();
+    }
+
+    // for when the state changes some other way than running, which makes all of it useless
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+        self.checkpoints.clear();
+    }
+}
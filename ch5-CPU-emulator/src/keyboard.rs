@@ -0,0 +1,218 @@
+// scripted keyboard input, so interactive programs can run deterministically
+use std::fmt;
+
+use crate::cpu::MemoryAccess;
+use crate::{Cpu, EmulatorError, StopReason};
+
+pub const NEWLINE: u16 = 128;
+pub const BACKSPACE: u16 = 129;
+pub const LEFT: u16 = 130;
+pub const UP: u16 = 131;
+pub const RIGHT: u16 = 132;
+pub const DOWN: u16 = 133;
+pub const HOME: u16 = 134;
+pub const END: u16 = 135;
+pub const PAGE_UP: u16 = 136;
+pub const PAGE_DOWN: u16 = 137;
+pub const INSERT: u16 = 138;
+pub const DELETE: u16 = 139;
+pub const ESC: u16 = 140;
+// F1 is 141, up to F12 at 152
+pub const F1: u16 = 141;
+
+// the Hack key code for a key name like `LEFT`, `F3` or `SPACE`, or a single printable character
+pub fn key_code(name: &str) -> Option<u16> {
+    let code = match name.to_ascii_uppercase().as_str() {
+        "NEWLINE" | "ENTER" => NEWLINE,
+        "BACKSPACE" => BACKSPACE,
+        "LEFT" => LEFT,
+        "UP" => UP,
+        "RIGHT" => RIGHT,
+        "DOWN" => DOWN,
+        "HOME" => HOME,
+        "END" => END,
+        "PAGEUP" => PAGE_UP,
+        "PAGEDOWN" => PAGE_DOWN,
+        "INSERT" => INSERT,
+        "DELETE" => DELETE,
+        "ESC" => ESC,
+        "SPACE" => b' ' as u16,
+        upper => match upper.strip_prefix('F').and_then(|n| n.parse::<u16>().ok()) {
+            Some(n @ 1..=12) => F1 + n - 1,
+            _ => return char_code(name.chars().next()?).filter(|_| name.chars().count() == 1),
+        },
+    };
+    Some(code)
+}
+
+// printable ASCII maps to itself, and newlines to the Hack newline key
+pub fn char_code(c: char) -> Option<u16> {
+    match c {
+        '\n' => Some(NEWLINE),
+        ' '..='~' => Some(c as u16),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub cycle: u64,
+    // 0 releases the key
+    pub key: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyScriptError {
+    // 1-based
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for KeyScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for KeyScriptError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    // sorted by cycle
+    events: Vec<KeyEvent>,
+    next: usize,
+}
+
+impl KeyScript {
+    pub fn new(mut events: Vec<KeyEvent>) -> Self {
+        // stable, so events on the same cycle keep their order
+        events.sort_by_key(|event| event.cycle);
+        Self { events, next: 0 }
+    }
+
+    // one event per line, `cycle N: press KEY` or `cycle N: release`, with `//` comments
+    pub fn parse(text: &str) -> Result<Self, KeyScriptError> {
+        let mut events = vec![];
+        for (line_no, line) in text.lines().enumerate() {
+            let error = |message: String| KeyScriptError { line: line_no + 1, message };
+            let line = line.split("//").next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (when, action) = line.split_once(':').ok_or_else(|| error("expected `cycle N: <action>`".to_string()))?;
+            let cycle = when.trim().strip_prefix("cycle")
+                .and_then(|n| n.trim().parse::<u64>().ok())
+                .ok_or_else(|| error(format!("invalid cycle `{}`", when.trim())))?;
+            let mut words = action.split_whitespace();
+            let key = match (words.next(), words.next(), words.next()) {
+                (Some("press"), Some(name), None) => key_code(name).ok_or_else(|| error(format!("unknown key `{name}`")))?,
+                (Some("release"), None, None) => 0,
+                _ => return Err(error(format!("expected `press KEY` or `release`, got `{}`", action.trim()))),
+            };
+            events.push(KeyEvent { cycle, key });
+        }
+        Ok(Self::new(events))
+    }
+
+    // types `text` starting at `start`, one key every `cycles_per_key` cycles, each held for half of that
+    pub fn typed(text: &str, start: u64, cycles_per_key: u64) -> Self {
+        let mut events = vec![];
+        for (idx, code) in text.chars().filter_map(char_code).enumerate() {
+            let cycle = start + idx as u64 * cycles_per_key;
+            events.push(KeyEvent { cycle, key: code });
+            events.push(KeyEvent { cycle: cycle + (cycles_per_key / 2).max(1), key: 0 });
+        }
+        Self::new(events)
+    }
+
+    pub fn extend(&mut self, other: KeyScript) {
+        let mut events = std::mem::take(&mut self.events);
+        events.extend(other.events);
+        *self = Self::new(events);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next >= self.events.len()
+    }
+
+    // the cycle of the next event that hasn't happened yet
+    pub fn next_cycle(&self) -> Option<u64> {
+        self.events.get(self.next).map(|event| event.cycle)
+    }
+
+    // goes back to before the events from this cycle on were applied, for when the CPU goes back in time
+    pub fn rewind(&mut self, cycle: u64) {
+        self.next = self.events.partition_point(|event| event.cycle < cycle);
+    }
+
+    // applies every event that is due by the CPU's current cycle
+    pub fn apply(&mut self, cpu: &mut Cpu) {
+        while let Some(event) = self.events.get(self.next).filter(|event| event.cycle <= cpu.cycles) {
+            cpu.set_key(event.key);
+            self.next += 1;
+        }
+    }
+
+    // like `Cpu::run`, pressing and releasing keys as it goes
+    pub fn run(&mut self, cpu: &mut Cpu, max_cycles: u64) -> Result<StopReason, EmulatorError> {
+        let end = cpu.cycles.saturating_add(max_cycles);
+        while cpu.cycles < end {
+            self.apply(cpu);
+            let until = self.next_cycle().map_or(end, |cycle| cycle.clamp(cpu.cycles + 1, end));
+            if cpu.run(until - cpu.cycles)? == StopReason::Halted {
+                return Ok(StopReason::Halted);
+            }
+        }
+        self.apply(cpu);
+        Ok(if cpu.is_halted() { StopReason::Halted } else { StopReason::CycleLimit })
+    }
+
+    // like `run` (or `run_until_halt` without a limit) but one instruction at a time,
+    // handing each one's RAM accesses to `on_step` right after it runs
+    pub fn run_traced(
+        &mut self,
+        cpu: &mut Cpu,
+        max_cycles: Option<u64>,
+        mut on_step: impl FnMut(&Cpu, MemoryAccess),
+    ) -> Result<StopReason, EmulatorError> {
+        let end = max_cycles.map(|max_cycles| cpu.cycles.saturating_add(max_cycles));
+        while end.is_none_or(|end| cpu.cycles < end) {
+            if cpu.is_halted() {
+                return Ok(StopReason::Halted);
+            }
+            self.apply(cpu);
+            let access = cpu.step_traced()?;
+            on_step(cpu, access);
+        }
+        self.apply(cpu);
+        Ok(if cpu.is_halted() { StopReason::Halted } else { StopReason::CycleLimit })
+    }
+
+    // like `Cpu::run_until_halt`, pressing and releasing keys as it goes
+    pub fn run_until_halt(&mut self, cpu: &mut Cpu) -> Result<(), EmulatorError> {
+        loop {
+            self.apply(cpu);
+            match self.next_cycle() {
+                Some(cycle) => {
+                    if cpu.run(cycle.saturating_sub(cpu.cycles).max(1))? == StopReason::Halted {
+                        return Ok(());
+                    }
+                },
+                None => return cpu.run_until_halt(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_limit_is_as_good_as_u64_max() {
+        let program = assembler::assemble("(END)\n@END\n0;JMP").unwrap();
+        let mut cpu = Cpu::new(program);
+        cpu.step().unwrap();
+        assert_eq!(KeyScript::default().run(&mut cpu, u64::MAX).unwrap(), StopReason::Halted);
+    }
+}
@@ -0,0 +1,28 @@
+pub mod coverage;
+pub mod cpu;
+pub mod dap;
+pub mod debug_info;
+pub mod decoded;
+pub mod device;
+pub mod debugger;
+pub mod gdb;
+pub mod history;
+pub mod keyboard;
+pub mod profile;
+pub mod screen;
+pub mod script;
+pub mod snapshot;
+pub mod trace;
+pub mod watch;
+
+use std::path::Path;
+
+use assembler::AssembleError;
+
+pub use crate::cpu::{Cpu, EmulatorError, StopReason};
+pub use crate::debug_info::{load_program_with_debug_info, DebugInfo};
+
+// loads a program either from .hack text or by assembling a .asm file
+pub fn load_program(path: impl AsRef<Path>) -> Result<Vec<u16>, AssembleError> {
+    load_program_with_debug_info(path).map(|(rom, _)| rom)
+}
@@ -0,0 +1,449 @@
+use std::{env::args, fs, io::{BufRead, BufReader, BufWriter, Write}, net::TcpListener, path::Path, process::exit};
+
+use assembler::AssembleError;
+use emulator::{debug_info, debugger::Debugger, keyboard::KeyScript, load_program_with_debug_info, screen, Cpu, DebugInfo, StopReason};
+use emulator::coverage::{self, Coverage};
+use emulator::dap::{self, DapServer};
+use emulator::device::parse_device;
+use emulator::gdb::GdbStub;
+use emulator::profile::{Profiler, Total};
+use emulator::script::{Outcome, ScriptRunner};
+use emulator::snapshot::Snapshot;
+use emulator::trace::{self, DiffMode, TraceEntry, TraceFormat};
+use emulator::watch::WriteRecord;
+
+fn load_or_exit(path: &Path) -> (Vec<u16>, Option<DebugInfo>) {
+    match load_program_with_debug_info(path) {
+        Ok(program) => program,
+        Err(AssembleError::Syntax(errors)) => {
+            for error in errors {
+                eprintln!("{}:{}", path.display(), error);
+            }
+            exit(1);
+        },
+        Err(AssembleError::Io(error)) => panic!("{error}"),
+    }
+}
+
+// removes `--name <value>` from the arguments, returning the value
+fn take_flag(cmd_args: &mut Vec<String>, name: &str) -> Option<String> {
+    let idx = cmd_args.iter().position(|arg| arg == name)?;
+    let value = cmd_args.get(idx + 1).unwrap_or_else(|| panic!("`{name}` needs a value")).clone();
+    cmd_args.drain(idx..=idx + 1);
+    Some(value)
+}
+
+// removes `--name` from the arguments, returning whether it was there
+fn take_switch(cmd_args: &mut Vec<String>, name: &str) -> bool {
+    let idx = cmd_args.iter().position(|arg| arg == name);
+    idx.map(|idx| cmd_args.remove(idx)).is_some()
+}
+
+// every `--device <kind>@<address>[:<arg>]`, see `device::parse_device`
+fn take_devices(cmd_args: &mut Vec<String>) -> Vec<String> {
+    let mut devices = vec![];
+    while let Some(spec) = take_flag(cmd_args, "--device") {
+        devices.push(spec);
+    }
+    devices
+}
+
+// a CPU with the program loaded and the devices attached, starting from the state saved in the
+// `--snapshot <file>` if there's one
+fn start_cpu(rom: Vec<u16>, snapshot: Option<String>, devices: &[String]) -> Cpu {
+    let mut cpu = Cpu::new(rom);
+    for spec in devices {
+        if let Err(error) = parse_device(spec).and_then(|(address, device)| cpu.attach(address, device)) {
+            eprintln!("--device {spec}: {error}");
+            exit(1);
+        }
+    }
+    if let Some(path) = snapshot {
+        if let Err(error) = Snapshot::load(&path).and_then(|snapshot| snapshot.restore(&mut cpu)) {
+            eprintln!("{path}: {error}");
+            exit(1);
+        }
+    }
+    cpu
+}
+
+fn parse_cycles(arg: &str) -> u64 {
+    arg.parse::<u64>().expect("the cycle count must be a number")
+}
+
+// `--keys <file>` for a file of key events, and/or `--type <text>` to type a string
+// starting at `--type-start` (0 by default), one key every `--type-rate` cycles (50000 by default)
+fn take_key_script(cmd_args: &mut Vec<String>) -> KeyScript {
+    let mut keys = KeyScript::default();
+    if let Some(path) = take_flag(cmd_args, "--keys") {
+        let text = fs::read_to_string(&path).expect("no such file");
+        keys = KeyScript::parse(&text).unwrap_or_else(|error| {
+            eprintln!("{path}:{error}");
+            exit(1);
+        });
+    }
+    let start = take_flag(cmd_args, "--type-start").map_or(0, |arg| parse_cycles(&arg));
+    let rate = take_flag(cmd_args, "--type-rate").map_or(50000, |arg| parse_cycles(&arg));
+    if let Some(text) = take_flag(cmd_args, "--type") {
+        keys.extend(KeyScript::typed(&text, start, rate));
+    }
+    keys
+}
+
+// runs for `max_cycles`, or until the program halts when there's no limit
+fn run_or_exit(cpu: &mut Cpu, keys: &mut KeyScript, max_cycles: Option<u64>) -> StopReason {
+    let result = match max_cycles {
+        Some(max_cycles) => keys.run(cpu, max_cycles),
+        None => keys.run_until_halt(cpu).map(|_| StopReason::Halted),
+    };
+    result.unwrap_or_else(|error| {
+        eprintln!("{error}");
+        exit(1);
+    })
+}
+
+// where to write coverage reports, from `--lcov <file>` and `--annotate <file>`
+struct CoverageReports {
+    lcov: Option<String>,
+    annotate: Option<String>,
+}
+
+impl CoverageReports {
+    fn take(cmd_args: &mut Vec<String>) -> Self {
+        Self { lcov: take_flag(cmd_args, "--lcov"), annotate: take_flag(cmd_args, "--annotate") }
+    }
+
+    fn wanted(&self) -> bool {
+        self.lcov.is_some() || self.annotate.is_some()
+    }
+
+    // per .asm line, and per .vm line when the program came from the VM translator
+    fn write(&self, coverage: &Coverage, info: Option<&DebugInfo>) {
+        let Some(info) = info else {
+            eprintln!("coverage needs the program's source, load a .asm file instead of a .hack one");
+            exit(1);
+        };
+        let mut files = vec![coverage.asm_lines(info)];
+        files.extend(coverage.vm_lines(info));
+        if let Some(lcov_path) = &self.lcov {
+            fs::write(lcov_path, coverage::to_lcov(&files)).expect("could not write the lcov file!");
+        }
+        if let Some(annotate_path) = &self.annotate {
+            let mut text = String::new();
+            for file in &files {
+                text.push_str(&format!("==> {} ({}/{} lines ran) <==\n", file.path.display(), file.lines_hit(), file.lines.len()));
+                match fs::read_to_string(&file.path) {
+                    Ok(source) => text.push_str(&coverage::annotate(file, &source)),
+                    Err(error) => text.push_str(&format!("could not read it: {error}\n")),
+                }
+                text.push('\n');
+            }
+            fs::write(annotate_path, text).expect("could not write the coverage report!");
+        }
+    }
+}
+
+// runs a .tst script, exiting with an error if its output doesn't match the .cmp file
+fn run_script(path: &Path, reports: CoverageReports) -> ! {
+    let script = fs::read_to_string(path).expect("no such file");
+    let mut runner = ScriptRunner::new(path.parent().unwrap_or(Path::new(".")));
+    if reports.wanted() {
+        runner.enable_coverage();
+    }
+    let result = runner.run(&script);
+    for text in &runner.echoed {
+        println!("{text}");
+    }
+    if let (Some(coverage), true) = (runner.coverage(), result.is_ok()) {
+        reports.write(coverage, runner.info());
+    }
+    match result {
+        Ok(Outcome::Passed) => {
+            println!("End of script - Comparison ended successfully");
+            exit(0);
+        },
+        Ok(Outcome::NoComparison) => {
+            println!("End of script");
+            exit(0);
+        },
+        Ok(Outcome::Mismatch { line, expected, actual }) => {
+            println!("Comparison failure at line {line}");
+            println!("expected: {expected}");
+            println!("     got: {actual}");
+            exit(1);
+        },
+        Err(error) => {
+            eprintln!("{}:{}", path.display(), error);
+            exit(1);
+        },
+    }
+}
+
+// `screen <program> <image> [--cycles N] [--compare <golden.pbm>]`: saves the screen once the program
+// halts (or after N cycles), and optionally fails if it doesn't match the golden image
+fn screen_command(mut cmd_args: Vec<String>) {
+    let mut keys = take_key_script(&mut cmd_args);
+    let max_cycles = take_flag(&mut cmd_args, "--cycles").map(|arg| parse_cycles(&arg));
+    let golden = take_flag(&mut cmd_args, "--compare");
+    let snapshot = take_flag(&mut cmd_args, "--snapshot");
+    let devices = take_devices(&mut cmd_args);
+    let [program, image] = &cmd_args[..] else {
+        panic!("usage: screen <program> <image> [--cycles N] [--compare <golden.pbm>]");
+    };
+
+    let mut cpu = start_cpu(load_or_exit(Path::new(program)).0, snapshot, &devices);
+    run_or_exit(&mut cpu, &mut keys, max_cycles);
+    screen::save(cpu.screen(), image).expect("could not save the screen image!");
+
+    if let Some(golden) = golden {
+        let golden_screen = screen::from_pbm(&fs::read(&golden).expect("no such file")).unwrap_or_else(|error| {
+            eprintln!("{golden}: {error}");
+            exit(1);
+        });
+        let diff = screen::diff(cpu.screen(), &golden_screen);
+        if let Some((top, left, bottom, right)) = diff.bounds {
+            println!("{} pixels differ from {golden}, between ({left}, {top}) and ({right}, {bottom})", diff.differing_pixels);
+            exit(1);
+        }
+        println!("the screen matches {golden}");
+    }
+}
+
+// `debug <program>`: an interactive debugger, see `help` inside it for the commands
+fn debug_command(mut cmd_args: Vec<String>) {
+    let keys = take_key_script(&mut cmd_args);
+    let snapshot = take_flag(&mut cmd_args, "--snapshot");
+    let devices = take_devices(&mut cmd_args);
+    let [program] = &cmd_args[..] else {
+        panic!("usage: debug <program>");
+    };
+    let path = Path::new(program);
+    let (rom, info) = load_or_exit(path);
+    let mut debugger = Debugger::new(start_cpu(rom, snapshot, &devices), info, keys);
+    debugger.run(&mut std::io::stdin().lock(), &mut std::io::stdout()).unwrap();
+}
+
+// `dap`: a Debug Adapter Protocol server on stdin/stdout, the program comes with the `launch` request
+fn dap_command() {
+    let messages = dap::spawn_reader(BufReader::new(std::io::stdin()));
+    if let Err(error) = DapServer::new(std::io::stdout(), messages).serve() {
+        eprintln!("{error}");
+        exit(1);
+    }
+}
+
+// `gdb <program> [--port N]`: waits for gdb to connect (`target remote :1234`) and lets it drive the program
+fn gdb_command(mut cmd_args: Vec<String>) {
+    let keys = take_key_script(&mut cmd_args);
+    let snapshot = take_flag(&mut cmd_args, "--snapshot");
+    let devices = take_devices(&mut cmd_args);
+    let port = take_flag(&mut cmd_args, "--port").map_or(1234, |arg| arg.parse::<u16>().expect("`--port` must be a port number"));
+    let [program] = &cmd_args[..] else {
+        panic!("usage: gdb <program> [--port N]");
+    };
+    let path = Path::new(program);
+    let (rom, info) = load_or_exit(path);
+    let debugger = Debugger::new(start_cpu(rom, snapshot, &devices), info, keys);
+    let listener = TcpListener::bind(("127.0.0.1", port)).expect("could not listen for gdb!");
+    println!("waiting for gdb on 127.0.0.1:{port}");
+    let (stream, _) = listener.accept().expect("could not accept the connection from gdb!");
+    let mut stub = GdbStub::new(debugger, stream).expect("could not set up the connection to gdb!");
+    if let Err(error) = stub.serve() {
+        eprintln!("connection to gdb lost: {error}");
+        exit(1);
+    }
+}
+
+// `trace <program> <file> [--json] [--cycles N]`: writes one line per instruction the program runs
+fn trace_command(mut cmd_args: Vec<String>) {
+    let mut keys = take_key_script(&mut cmd_args);
+    let max_cycles = take_flag(&mut cmd_args, "--cycles").map(|arg| parse_cycles(&arg));
+    let format = if take_switch(&mut cmd_args, "--json") { TraceFormat::Json } else { TraceFormat::Text };
+    let snapshot = take_flag(&mut cmd_args, "--snapshot");
+    let devices = take_devices(&mut cmd_args);
+    let [program, trace_path] = &cmd_args[..] else {
+        panic!("usage: trace <program> <file> [--json] [--cycles N]");
+    };
+
+    let mut cpu = start_cpu(load_or_exit(Path::new(program)).0, snapshot, &devices);
+    let mut out = BufWriter::new(fs::File::create(trace_path).expect("could not create the trace file!"));
+    let result = keys.run_traced(&mut cpu, max_cycles, |cpu, access| {
+        writeln!(out, "{}", TraceEntry::new(cpu, &access).format(format)).expect("could not write the trace file!");
+    });
+    out.flush().expect("could not write the trace file!");
+    if let Err(error) = result {
+        eprintln!("{error}");
+        exit(1);
+    }
+}
+
+fn read_trace(path: &str) -> impl Iterator<Item = TraceEntry> + '_ {
+    let file = BufReader::new(fs::File::open(path).expect("no such file"));
+    file.lines().enumerate().filter_map(move |(line_no, line)| {
+        let line = line.expect("could not read the trace file!");
+        trace::parse_trace_line(&line, line_no).unwrap_or_else(|error| {
+            eprintln!("{path}:{error}");
+            exit(1);
+        })
+    })
+}
+
+// `trace-diff <left> <right> [--writes]`: reports where two traces first disagree, comparing every
+// cycle or, with `--writes`, only the order and values of RAM writes
+fn trace_diff_command(mut cmd_args: Vec<String>) {
+    let mode = if take_switch(&mut cmd_args, "--writes") { DiffMode::Writes } else { DiffMode::Cycles };
+    let [left_path, right_path] = &cmd_args[..] else {
+        panic!("usage: trace-diff <left> <right> [--writes]");
+    };
+    let Some(divergence) = trace::first_divergence(read_trace(left_path), read_trace(right_path), mode) else {
+        println!("the traces match");
+        return;
+    };
+    let show = |entry: &Option<TraceEntry>| entry.as_ref().map_or("<end of trace>".to_string(), |entry| entry.to_string());
+    match &divergence.last_match {
+        Some((left, right)) => {
+            println!("last match:");
+            println!("  {left_path}: {left}");
+            println!("  {right_path}: {right}");
+        },
+        None => println!("the traces differ from the start"),
+    }
+    println!("first difference:");
+    println!("  {left_path}: {}", show(&divergence.left));
+    println!("  {right_path}: {}", show(&divergence.right));
+    exit(1);
+}
+
+fn print_totals(title: &str, totals: &[Total], total_cycles: u64, top: usize) {
+    println!("{title}:");
+    for total in totals.iter().take(top) {
+        let share = total.cycles as f64 * 100.0 / total_cycles.max(1) as f64;
+        println!("{:>12} {share:>6.2}% {:>10}x  {}", total.cycles, total.entries, total.name);
+    }
+    println!();
+}
+
+// `profile <program> [--cycles N] [--top N] [--folded <file>]`: runs the program and reports where the
+// cycles went; `--folded` also writes the call stacks for flamegraph tools
+fn profile_command(mut cmd_args: Vec<String>) {
+    let mut keys = take_key_script(&mut cmd_args);
+    let max_cycles = take_flag(&mut cmd_args, "--cycles").map(|arg| parse_cycles(&arg));
+    let top = take_flag(&mut cmd_args, "--top").map_or(10, |arg| arg.parse::<usize>().expect("`--top` must be a number"));
+    let folded_path = take_flag(&mut cmd_args, "--folded");
+    let snapshot = take_flag(&mut cmd_args, "--snapshot");
+    let devices = take_devices(&mut cmd_args);
+    let [program] = &cmd_args[..] else {
+        panic!("usage: profile <program> [--cycles N] [--top N] [--folded <file>]");
+    };
+
+    let (rom, info) = load_or_exit(Path::new(program));
+    let mut profiler = Profiler::new(&rom, info.as_ref());
+    let mut cpu = start_cpu(rom, snapshot, &devices);
+    let result = keys.run_traced(&mut cpu, max_cycles, |cpu, access| profiler.record(cpu, &access));
+    if let Err(error) = result {
+        eprintln!("{error}");
+        exit(1);
+    }
+
+    let total_cycles = profiler.total_cycles();
+    println!("{total_cycles} cycles\n");
+    if let Some(info) = &info {
+        print_totals("by label (cycles, share, entries)", &profiler.label_totals(info), total_cycles, top);
+        let commands = profiler.annotation_totals(info);
+        if !commands.is_empty() {
+            print_totals("by VM command (cycles, share, times run)", &commands, total_cycles, top);
+        }
+    }
+    println!("hottest loops (cycles, iterations):");
+    for hot_loop in profiler.hottest_loops().iter().take(top) {
+        let label = info.as_ref().and_then(|info| info.label_region(hot_loop.start)).map_or(String::new(), |(label, _)| format!("  {label}"));
+        println!("{:>12} {:>10}x  ROM[{}..{}]{label}", hot_loop.cycles, hot_loop.iterations, hot_loop.start, hot_loop.end);
+    }
+    println!();
+    println!("hottest instructions (cycles):");
+    let mut addresses = (0..profiler.counts().len()).filter(|&address| profiler.counts()[address] > 0).collect::<Vec<_>>();
+    addresses.sort_by_key(|&address| std::cmp::Reverse(profiler.counts()[address]));
+    for &address in addresses.iter().take(top) {
+        println!("{:>12}  {}", profiler.counts()[address], debug_info::describe_instruction(cpu.rom(), info.as_ref(), address as u16));
+    }
+
+    if let Some(folded_path) = folded_path {
+        let mut out = BufWriter::new(fs::File::create(&folded_path).expect("could not create the folded stacks file!"));
+        for line in profiler.collapsed_stacks(info.as_ref()) {
+            writeln!(out, "{line}").expect("could not write the folded stacks file!");
+        }
+    }
+}
+
+fn main() {
+    let mut cmd_args = args().collect::<Vec<String>>();
+    // 1st arg is always cwd, so we get the 2nd
+    let first_arg = cmd_args.get(1).expect("no program was provided!");
+    match first_arg.as_str() {
+        "screen" => return screen_command(cmd_args[2..].to_vec()),
+        "debug" => return debug_command(cmd_args[2..].to_vec()),
+        "gdb" => return gdb_command(cmd_args[2..].to_vec()),
+        "dap" => return dap_command(),
+        "trace" => return trace_command(cmd_args[2..].to_vec()),
+        "profile" => return profile_command(cmd_args[2..].to_vec()),
+        "trace-diff" => return trace_diff_command(cmd_args[2..].to_vec()),
+        _ => {},
+    }
+    let path = Path::new(first_arg).to_path_buf();
+    let reports = CoverageReports::take(&mut cmd_args);
+    if path.extension().is_some_and(|ext| ext == "tst") {
+        run_script(&path, reports);
+    }
+
+    let mut keys = take_key_script(&mut cmd_args);
+    let trace_path = take_flag(&mut cmd_args, "--trace-writes");
+    let save_snapshot = take_flag(&mut cmd_args, "--save-snapshot");
+    let snapshot = take_flag(&mut cmd_args, "--snapshot");
+    let devices = take_devices(&mut cmd_args);
+    let (rom, info) = load_or_exit(&path);
+    let mut cpu = start_cpu(rom, snapshot, &devices);
+    let max_cycles = cmd_args.get(2).map(|arg| parse_cycles(arg));
+    let stop = if trace_path.is_some() || reports.wanted() {
+        // one `cycle pc address old new` line per RAM write, with the address' symbol at the end when it has one
+        let mut trace = trace_path.map(|trace_path| BufWriter::new(fs::File::create(trace_path).expect("could not create the trace file!")));
+        let mut coverage = Coverage::new(cpu.rom().len());
+        let result = keys.run_traced(&mut cpu, max_cycles, |cpu, access| {
+            coverage.record(access.pc);
+            let (Some(trace), Some(write)) = (&mut trace, access.write) else { return };
+            let record = WriteRecord { cycle: cpu.cycles, pc: access.pc, write };
+            match info.as_ref().and_then(|info| info.ram_name(write.address)) {
+                Some(name) => writeln!(trace, "{record} {name}"),
+                None => writeln!(trace, "{record}"),
+            }.expect("could not write the trace file!");
+        });
+        if let Some(trace) = &mut trace {
+            trace.flush().expect("could not write the trace file!");
+        }
+        let stop = result.unwrap_or_else(|error| {
+            eprintln!("{error}");
+            exit(1);
+        });
+        if reports.wanted() {
+            reports.write(&coverage, info.as_ref());
+        }
+        stop
+    } else {
+        run_or_exit(&mut cpu, &mut keys, max_cycles)
+    };
+    if let Some(save_snapshot) = save_snapshot {
+        Snapshot::of(&cpu).save(&save_snapshot).expect("could not save the snapshot!");
+    }
+    match (stop, cpu.exit_code()) {
+        (_, Some(code)) => println!("exited with code {code} after {} cycles", cpu.cycles),
+        (StopReason::Halted, None) => println!("halted after {} cycles", cpu.cycles),
+        (StopReason::CycleLimit, None) => println!("stopped after {} cycles", cpu.cycles),
+    }
+    println!("A={} D={} PC={}", cpu.a, cpu.d, cpu.pc);
+    for (address, value) in cpu.ram()[..16].iter().enumerate() {
+        println!("RAM[{address}] = {}", *value as i16);
+    }
+    if let Some(code) = cpu.exit_code() {
+        // the host only keeps 8 bits of it, and 256 shouldn't come out as success
+        exit(if code <= 255 { code as i32 } else { 1 });
+    }
+}
@@ -0,0 +1,198 @@
+// counts what a program spends its cycles on: per instruction, per label, per loop and per VM function call stack
+use std::collections::HashMap;
+
+use assembler::instruction::{Instruction, JumpIf};
+use assembler::symbols::SymbolKind;
+
+use crate::cpu::MemoryAccess;
+use crate::debug_info;
+use crate::{Cpu, DebugInfo};
+
+// a VM function that was called and hasn't returned yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Frame {
+    function: String,
+    return_address: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    // executions per ROM address
+    counts: Vec<u64>,
+    // (jump, target) -> times a backward jump was taken
+    back_edges: HashMap<(u16, u16), u64>,
+    // per ROM address, whether it's a `0;JMP`-style jump, which can land on the very next instruction
+    unconditional_jumps: Vec<bool>,
+    // ROM address -> VM function that starts there
+    functions: HashMap<u16, String>,
+    stack: Vec<Frame>,
+    // every distinct call stack seen, by id, and the id of the current one
+    stacks: Vec<Vec<String>>,
+    stack_ids: HashMap<Vec<String>, usize>,
+    stack_id: usize,
+    // (stack id, ROM address) -> executions
+    stack_counts: HashMap<(usize, u16), u64>,
+}
+
+// the cycles spent in one part of the program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Total {
+    pub name: String,
+    pub cycles: u64,
+    // how many times it was entered, when that makes sense
+    pub entries: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    // the start of the loop, and the jump back to it
+    pub start: u16,
+    pub end: u16,
+    pub iterations: u64,
+    // every instruction run between the two, over all iterations
+    pub cycles: u64,
+}
+
+impl Profiler {
+    pub fn new(rom: &[u16], info: Option<&DebugInfo>) -> Self {
+        let functions = info.map_or(HashMap::new(), |info| {
+            info.symbols.iter()
+                .filter(|(name, symbol)| symbol.kind == SymbolKind::Label && debug_info::is_vm_function(name))
+                .map(|(name, symbol)| (symbol.address, name.clone()))
+                .collect()
+        });
+        let unconditional_jumps = rom.iter()
+            .map(|&word| matches!(Instruction::decode(word), Some(Instruction::C(c_instr)) if c_instr.jump_if == JumpIf::Jmp))
+            .collect();
+        let mut profiler = Self { counts: vec![0; rom.len()], unconditional_jumps, functions, ..Default::default() };
+        profiler.stacks.push(vec![]);
+        profiler.stack_ids.insert(vec![], 0);
+        profiler
+    }
+
+    fn update_stack_id(&mut self) {
+        let functions = self.stack.iter().map(|frame| frame.function.clone()).collect::<Vec<_>>();
+        self.stack_id = match self.stack_ids.get(&functions) {
+            Some(&id) => id,
+            None => {
+                self.stacks.push(functions.clone());
+                self.stack_ids.insert(functions, self.stacks.len() - 1);
+                self.stacks.len() - 1
+            },
+        };
+    }
+
+    // call it after each instruction, with what `Cpu::step_traced` returned for it
+    pub fn record(&mut self, cpu: &Cpu, access: &MemoryAccess) {
+        let pc = access.pc;
+        if let Some(count) = self.counts.get_mut(pc as usize) {
+            *count += 1;
+        }
+        *self.stack_counts.entry((self.stack_id, pc)).or_default() += 1;
+
+        let jumped = cpu.pc != pc.wrapping_add(1) || self.unconditional_jumps.get(pc as usize) == Some(&true);
+        if !jumped {
+            return;
+        }
+        // `call` pushes the address right after its jump and `return` jumps back there, so a jump to
+        // a pending return address unwinds to that frame, while a jump to a function's label enters it
+        if let Some(depth) = self.stack.iter().rposition(|frame| frame.return_address == cpu.pc) {
+            self.stack.truncate(depth);
+            self.update_stack_id();
+        } else if let Some(function) = self.functions.get(&cpu.pc) {
+            self.stack.push(Frame { function: function.clone(), return_address: pc.wrapping_add(1) });
+            self.update_stack_id();
+        } else if cpu.pc <= pc {
+            *self.back_edges.entry((pc, cpu.pc)).or_default() += 1;
+        }
+    }
+
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // cycles per label region (everything from a label up to the next one), most expensive first
+    pub fn label_totals(&self, info: &DebugInfo) -> Vec<Total> {
+        let mut totals: HashMap<String, Total> = HashMap::new();
+        for (address, &cycles) in self.counts.iter().enumerate().filter(|(_, &cycles)| cycles > 0) {
+            let (name, start) = info.label_region(address as u16).map_or(("<start>".to_string(), 0), |(name, start)| (name.to_string(), start));
+            let total = totals.entry(name.clone()).or_insert(Total { name, cycles: 0, entries: 0 });
+            total.cycles += cycles;
+            if address == start as usize {
+                total.entries = cycles;
+            }
+        }
+        sorted(totals.into_values().collect())
+    }
+
+    // cycles per VM command, going by the comment the translator writes above each command's code
+    // (see `debug_info::vm_location`); arguments that are plain numbers are dropped, so all the `push constant n` add up together
+    pub fn annotation_totals(&self, info: &DebugInfo) -> Vec<Total> {
+        let mut totals: HashMap<String, Total> = HashMap::new();
+        let mut previous = None;
+        for (address, annotation) in info.annotations().into_iter().enumerate() {
+            let Some((line, annotation)) = annotation else { continue };
+            let command = debug_info::vm_location(annotation).map_or(annotation, |location| location.command);
+            let name = command.split_whitespace().filter(|word| word.parse::<i64>().is_err()).collect::<Vec<_>>().join(" ");
+            let cycles = self.counts.get(address).copied().unwrap_or(0);
+            let total = totals.entry(name.clone()).or_insert(Total { name, cycles: 0, entries: 0 });
+            total.cycles += cycles;
+            // the first instruction of each command's code tells how many times the command ran
+            if previous != Some(line) {
+                total.entries += cycles;
+            }
+            previous = Some(line);
+        }
+        sorted(totals.into_values().filter(|total| total.cycles > 0).collect())
+    }
+
+    // the loops that ran the most instructions, from the backward jumps that were taken
+    pub fn hottest_loops(&self) -> Vec<Loop> {
+        let mut loops = self.back_edges.iter()
+            .map(|(&(end, start), &iterations)| Loop {
+                start,
+                end,
+                iterations,
+                cycles: self.counts.get(start as usize..=end as usize).map_or(0, |counts| counts.iter().sum()),
+            })
+            .collect::<Vec<_>>();
+        loops.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.start.cmp(&b.start)).then(a.end.cmp(&b.end)));
+        loops
+    }
+
+    // `Sys.init;Main.main;LOOP 1234` lines, the input flamegraph tools expect: the VM call stack,
+    // then where in the function the cycles went (the VM command when it's known, otherwise the label)
+    pub fn collapsed_stacks(&self, info: Option<&DebugInfo>) -> Vec<String> {
+        let annotations = info.map_or(vec![], |info| info.annotations());
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        for (&(stack_id, address), &cycles) in &self.stack_counts {
+            let mut frames = self.stacks[stack_id].clone();
+            let leaf = match annotations.get(address as usize).copied().flatten() {
+                Some((_, annotation)) => debug_info::vm_location(annotation).map_or(annotation, |location| location.command).to_string(),
+                None => match info.and_then(|info| info.label_region(address)) {
+                    Some((label, _)) => label.to_string(),
+                    None => format!("ROM[{address}]"),
+                },
+            };
+            // code right at the top of a function is already named by the frame
+            if frames.last() != Some(&leaf) {
+                frames.push(leaf);
+            }
+            // `;` separates frames, so it can't appear inside one
+            let key = frames.iter().map(|frame| frame.replace(';', ",")).collect::<Vec<_>>().join(";");
+            *folded.entry(key).or_default() += cycles;
+        }
+        let mut lines = folded.into_iter().map(|(stack, cycles)| format!("{stack} {cycles}")).collect::<Vec<_>>();
+        lines.sort();
+        lines
+    }
+}
+
+fn sorted(mut totals: Vec<Total>) -> Vec<Total> {
+    totals.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.name.cmp(&b.name)));
+    totals
+}
@@ -0,0 +1,188 @@
+// renders the SCREEN memory map to images, and compares it against golden images
+use std::{fs, io, path::Path};
+
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+// 16 pixels per word
+pub const WORDS_PER_ROW: usize = WIDTH / 16;
+
+// whether the pixel is black; the leftmost pixel of each word is its least significant bit
+pub fn pixel(screen: &[u16], row: usize, col: usize) -> bool {
+    screen[row * WORDS_PER_ROW + col / 16] >> (col % 16) & 1 == 1
+}
+
+fn set_pixel(screen: &mut [u16], row: usize, col: usize) {
+    screen[row * WORDS_PER_ROW + col / 16] |= 1 << (col % 16);
+}
+
+// packs each row into bytes, 8 pixels per byte with the leftmost one in the most significant bit
+fn packed_rows(screen: &[u16], black: bool) -> impl Iterator<Item = Vec<u8>> + '_ {
+    (0..HEIGHT).map(move |row| {
+        (0..WIDTH / 8).map(|byte| {
+            (0..8).fold(0u8, |acc, bit| acc << 1 | (pixel(screen, row, byte * 8 + bit) == black) as u8)
+        }).collect()
+    })
+}
+
+// binary PBM (P4), where 1 is black just like in the Hack screen
+pub fn to_pbm(screen: &[u16]) -> Vec<u8> {
+    let mut out = format!("P4\n{WIDTH} {HEIGHT}\n").into_bytes();
+    for row in packed_rows(screen, true) {
+        out.extend(row);
+    }
+    out
+}
+
+// reads back a plain (P1) or binary (P4) 512x256 PBM
+pub fn from_pbm(data: &[u8]) -> Result<Vec<u16>, String> {
+    // the header is 3 whitespace separated fields, and comments can show up in between
+    let mut fields = vec![];
+    let mut pos = 0;
+    while fields.len() < 3 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() { pos += 1; }
+        if data.get(pos) == Some(&b'#') {
+            while pos < data.len() && data[pos] != b'\n' { pos += 1; }
+            continue;
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() { pos += 1; }
+        if start == pos {
+            return Err("truncated PBM header".to_string());
+        }
+        fields.push(String::from_utf8_lossy(&data[start..pos]).to_string());
+    }
+    if fields[1] != WIDTH.to_string() || fields[2] != HEIGHT.to_string() {
+        return Err(format!("the image is {}x{}, not {WIDTH}x{HEIGHT}", fields[1], fields[2]));
+    }
+
+    let mut screen = vec![0; WORDS_PER_ROW * HEIGHT];
+    match fields[0].as_str() {
+        "P4" => {
+            // a single whitespace character separates the header from the pixels
+            let pixels = data.get(pos + 1..).filter(|pixels| pixels.len() >= WIDTH / 8 * HEIGHT).ok_or("truncated PBM data")?;
+            for row in 0..HEIGHT {
+                for col in 0..WIDTH {
+                    if pixels[row * WIDTH / 8 + col / 8] >> (7 - col % 8) & 1 == 1 {
+                        set_pixel(&mut screen, row, col);
+                    }
+                }
+            }
+        },
+        "P1" => {
+            let bits = data[pos..].iter().filter(|c| matches!(c, b'0' | b'1')).collect::<Vec<_>>();
+            if bits.len() < WIDTH * HEIGHT {
+                return Err("truncated PBM data".to_string());
+            }
+            for (idx, bit) in bits.iter().take(WIDTH * HEIGHT).enumerate() {
+                if **bit == b'1' {
+                    set_pixel(&mut screen, idx / WIDTH, idx % WIDTH);
+                }
+            }
+        },
+        other => return Err(format!("`{other}` is not a PBM image")),
+    }
+    Ok(screen)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { crc >> 1 ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend((data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend(kind);
+    out.extend(data);
+    let crc = crc32(&out[start..]);
+    out.extend(crc.to_be_bytes());
+}
+
+// 1-bit grayscale PNG; the pixels go into uncompressed deflate blocks, which keeps this dependency free
+pub fn to_png(screen: &[u16]) -> Vec<u8> {
+    let mut raw = vec![];
+    // in grayscale 0 is black, so the bits are flipped compared to the Hack screen
+    for row in packed_rows(screen, false) {
+        // filter type 0 (none) in front of every row
+        raw.push(0);
+        raw.extend(row);
+    }
+
+    // zlib header, then stored deflate blocks of at most 65535 bytes, then the checksum
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(65535).collect::<Vec<_>>();
+    for (idx, block) in blocks.iter().enumerate() {
+        zlib.push((idx == blocks.len() - 1) as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend(*block);
+    }
+    zlib.extend(adler32(&raw).to_be_bytes());
+
+    let mut header = vec![];
+    header.extend((WIDTH as u32).to_be_bytes());
+    header.extend((HEIGHT as u32).to_be_bytes());
+    // bit depth 1, color type 0 (grayscale), default compression/filter, no interlacing
+    header.extend([1, 0, 0, 0, 0]);
+
+    let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    png_chunk(&mut out, b"IHDR", &header);
+    png_chunk(&mut out, b"IDAT", &zlib);
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+// picks the format from the extension: .png, anything else is PBM
+pub fn save(screen: &[u16], path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let data = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => to_png(screen),
+        _ => to_pbm(screen),
+    };
+    fs::write(path, data)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenDiff {
+    pub differing_pixels: usize,
+    // (top, left, bottom, right) of the differing pixels, inclusive
+    pub bounds: Option<(usize, usize, usize, usize)>,
+}
+
+impl ScreenDiff {
+    pub fn is_identical(&self) -> bool {
+        self.differing_pixels == 0
+    }
+}
+
+pub fn diff(screen: &[u16], golden: &[u16]) -> ScreenDiff {
+    let mut differing_pixels = 0;
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for row in 0..HEIGHT {
+        for col in 0..WIDTH {
+            if pixel(screen, row, col) != pixel(golden, row, col) {
+                differing_pixels += 1;
+                bounds = Some(match bounds {
+                    Some((top, left, bottom, right)) => (top.min(row), left.min(col), bottom.max(row), right.max(col)),
+                    None => (row, col, row, col),
+                });
+            }
+        }
+    }
+    ScreenDiff { differing_pixels, bounds }
+}
@@ -0,0 +1,557 @@
+// interpreter for the CPU emulator's test scripts (.tst), producing the .out file
+// and checking it line by line against the .cmp file
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use assembler::AssembleError;
+
+use crate::coverage::Coverage;
+use crate::snapshot::Snapshot;
+use crate::{load_program_with_debug_info, Cpu, DebugInfo};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    // 1-based line in the script
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    // every output line matched the .cmp file
+    Passed,
+    // there was no `compare-to` to check against
+    NoComparison,
+    // 1-based line in the .out/.cmp files
+    Mismatch { line: usize, expected: String, actual: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    A,
+    D,
+    PC,
+    Ram(u16),
+    Time,
+}
+
+impl Variable {
+    fn parse(word: &str) -> Option<Self> {
+        match word {
+            "A" => Some(Variable::A),
+            "D" => Some(Variable::D),
+            "PC" => Some(Variable::PC),
+            "time" => Some(Variable::Time),
+            _ => {
+                let index = word.strip_prefix("RAM[")?.strip_suffix(']')?;
+                index.parse::<u16>().ok().filter(|&index| (index as usize) < crate::cpu::RAM_SIZE).map(Variable::Ram)
+            },
+        }
+    }
+
+    fn get(&self, cpu: &Cpu) -> i64 {
+        match self {
+            Variable::A => cpu.a as i16 as i64,
+            Variable::D => cpu.d as i16 as i64,
+            Variable::PC => cpu.pc as i64,
+            Variable::Ram(address) => cpu.read(*address) as i16 as i64,
+            Variable::Time => cpu.cycles as i64,
+        }
+    }
+
+    fn set(&self, cpu: &mut Cpu, value: u16) -> Result<(), String> {
+        match self {
+            Variable::A => cpu.a = value,
+            Variable::D => cpu.d = value,
+            Variable::PC => cpu.pc = value,
+            // `set` can also poke the keyboard register, unlike the program itself
+            Variable::Ram(address) if *address == crate::cpu::KBD => cpu.set_key(value),
+            Variable::Ram(address) => cpu.write(*address, value),
+            Variable::Time => return Err("`time` can't be set".to_string()),
+        }
+        Ok(())
+    }
+}
+
+// one column of `output-list`, e.g. `RAM[256]%D1.6.1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OutputColumn<'a> {
+    name: &'a str,
+    variable: Variable,
+    format: char,
+    left: usize,
+    width: usize,
+    right: usize,
+}
+
+impl<'a> OutputColumn<'a> {
+    fn parse(word: &'a str) -> Option<Self> {
+        let (name, format) = word.split_once('%').unwrap_or((word, "D1.6.1"));
+        let variable = Variable::parse(name)?;
+        let mut chars = format.chars();
+        let format_char = chars.next().filter(|c| matches!(c, 'D' | 'B' | 'X' | 'S'))?;
+        let sizes = chars.as_str().split('.').map(|n| n.parse::<usize>().ok()).collect::<Option<Vec<_>>>()?;
+        let [left, width, right] = sizes[..] else { return None };
+        Some(Self { name, variable, format: format_char, left, width, right })
+    }
+
+    // the name, centered and cut to fit the column
+    fn header(&self) -> String {
+        let total = self.left + self.width + self.right;
+        let name = &self.name[..self.name.len().min(total)];
+        let left = (total - name.len()) / 2;
+        format!("{}{}{}", " ".repeat(left), name, " ".repeat(total - left - name.len()))
+    }
+
+    fn value(&self, cpu: &Cpu) -> String {
+        let value = self.variable.get(cpu);
+        let text = match self.format {
+            'B' => format!("{:016b}", value as u16),
+            'X' => format!("{:04X}", value as u16),
+            _ => value.to_string(),
+        };
+        // numbers are right-aligned, and cut from the left when they don't fit
+        let text = &text[text.len().saturating_sub(self.width)..];
+        format!("{}{:>width$}{}", " ".repeat(self.left), text, " ".repeat(self.right), width = self.width)
+    }
+}
+
+// a number in the script, in decimal or as `%D`, `%X` or `%B` followed by digits
+fn parse_value(word: &str) -> Option<u16> {
+    let (radix, digits) = match word.strip_prefix('%') {
+        Some(rest) => match rest.split_at(rest.len().min(1)) {
+            ("D", digits) => (10, digits),
+            ("X", digits) => (16, digits),
+            ("B", digits) => (2, digits),
+            _ => return None,
+        },
+        None => (10, word),
+    };
+    if radix == 10 {
+        digits.parse::<i16>().map(|value| value as u16).ok().or_else(|| digits.parse::<u16>().ok())
+    } else {
+        u16::from_str_radix(digits, radix).ok()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+    OpenBrace,
+    CloseBrace,
+    // `,` or `;`, they both end a command
+    End,
+}
+
+fn tokenize(script: &str) -> Result<Vec<(Token, usize)>, ScriptError> {
+    let mut tokens = vec![];
+    let mut chars = script.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {},
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            },
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => {
+                            if c == '\n' { line += 1; }
+                            last = c;
+                        },
+                        None => return Err(ScriptError { line, message: "unterminated comment".to_string() }),
+                    }
+                }
+            },
+            '{' => tokens.push((Token::OpenBrace, line)),
+            '}' => tokens.push((Token::CloseBrace, line)),
+            ',' | ';' => tokens.push((Token::End, line)),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => return Err(ScriptError { line, message: "unterminated string".to_string() }),
+                        Some(c) => text.push(c),
+                    }
+                }
+                tokens.push((Token::Str(text), line));
+            },
+            _ => {
+                let mut word = String::from(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !matches!(c, ',' | ';' | '{' | '}' | '"')) {
+                    word.push(c);
+                }
+                tokens.push((Token::Word(word), line));
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Comparison {
+    Less,
+    LessEq,
+    Eq,
+    NotEq,
+    GreaterEq,
+    Greater,
+}
+
+impl Comparison {
+    // `=`/`<>` like the scripts use, and `==`/`!=` too
+    pub(crate) fn parse(word: &str) -> Option<Self> {
+        match word {
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessEq),
+            "=" | "==" => Some(Comparison::Eq),
+            "<>" | "!=" => Some(Comparison::NotEq),
+            ">=" => Some(Comparison::GreaterEq),
+            ">" => Some(Comparison::Greater),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn holds(&self, left: i64, right: i64) -> bool {
+        match self {
+            Comparison::Less => left < right,
+            Comparison::LessEq => left <= right,
+            Comparison::Eq => left == right,
+            Comparison::NotEq => left != right,
+            Comparison::GreaterEq => left >= right,
+            Comparison::Greater => left > right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Load(String),
+    // not in the official tools: starts from a snapshot of the loaded program, see `snapshot.rs`
+    LoadSnapshot(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<String>),
+    Output,
+    Set(Variable, u16),
+    Repeat(u64, Vec<(Command, usize)>),
+    While(Variable, Comparison, i64, Vec<(Command, usize)>),
+    TickTock,
+    Echo(String),
+    ClearEcho,
+}
+
+struct CommandParser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl CommandParser {
+    fn line(&self) -> usize {
+        self.tokens.get(self.pos).or(self.tokens.last()).map_or(1, |(_, line)| *line)
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, ScriptError> {
+        Err(ScriptError { line: self.line(), message })
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(token, _)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn word(&mut self, what: &str) -> Result<String, ScriptError> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word),
+            _ => { self.pos -= 1; self.error(format!("expected {what}")) },
+        }
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<(), ScriptError> {
+        if self.next() == Some(expected) {
+            Ok(())
+        } else {
+            self.pos -= 1;
+            self.error(format!("expected {what}"))
+        }
+    }
+
+    fn variable(&mut self) -> Result<Variable, ScriptError> {
+        let word = self.word("a variable")?;
+        Variable::parse(&word).map_or_else(|| self.error(format!("unknown variable `{word}`")), Ok)
+    }
+
+    fn block(&mut self) -> Result<Vec<(Command, usize)>, ScriptError> {
+        self.expect(Token::OpenBrace, "`{`")?;
+        let mut commands = vec![];
+        loop {
+            match self.tokens.get(self.pos) {
+                Some((Token::CloseBrace, _)) => { self.pos += 1; break; },
+                Some(_) => commands.extend(self.command()?),
+                None => return self.error("missing `}`".to_string()),
+            }
+        }
+        Ok(commands)
+    }
+
+    // parses one command along with its line, or None for a stray separator
+    fn command(&mut self) -> Result<Option<(Command, usize)>, ScriptError> {
+        let line = self.line();
+        let name = match self.next() {
+            Some(Token::End) => return Ok(None),
+            Some(Token::Word(name)) => name,
+            _ => { self.pos -= 1; return self.error("expected a command".to_string()); },
+        };
+        let command = match name.as_str() {
+            "load" => Command::Load(self.word("a file name")?),
+            "load-snapshot" => Command::LoadSnapshot(self.word("a file name")?),
+            "output-file" => Command::OutputFile(self.word("a file name")?),
+            "compare-to" => Command::CompareTo(self.word("a file name")?),
+            "output-list" => {
+                let mut columns = vec![];
+                while let Some((Token::Word(_), _)) = self.tokens.get(self.pos) {
+                    let column = self.word("an output column")?;
+                    if OutputColumn::parse(&column).is_none() {
+                        self.pos -= 1;
+                        return self.error(format!("invalid output column `{column}`"));
+                    }
+                    columns.push(column);
+                }
+                Command::OutputList(columns)
+            },
+            "output" => Command::Output,
+            "set" => {
+                let variable = self.variable()?;
+                let value = self.word("a value")?;
+                let value = parse_value(&value).map_or_else(|| self.error(format!("invalid value `{value}`")), Ok)?;
+                Command::Set(variable, value)
+            },
+            "repeat" => {
+                let count = self.word("a repeat count")?;
+                let count = count.parse::<u64>().map_or_else(|_| self.error(format!("invalid repeat count `{count}`")), Ok)?;
+                return Ok(Some((Command::Repeat(count, self.block()?), line)));
+            },
+            "while" => {
+                let variable = self.variable()?;
+                let comparison = self.word("a comparison")?;
+                let comparison = Comparison::parse(&comparison).map_or_else(|| self.error(format!("unknown comparison `{comparison}`")), Ok)?;
+                let value = self.word("a value")?;
+                let value = parse_value(&value).map_or_else(|| self.error(format!("invalid value `{value}`")), Ok)?;
+                return Ok(Some((Command::While(variable, comparison, value as i16 as i64, self.block()?), line)));
+            },
+            "ticktock" => Command::TickTock,
+            "echo" => match self.next() {
+                Some(Token::Str(text) | Token::Word(text)) => Command::Echo(text),
+                _ => { self.pos -= 1; return self.error("expected the text to echo".to_string()); },
+            },
+            "clear-echo" => Command::ClearEcho,
+            other => return Err(ScriptError { line, message: format!("unsupported command `{other}`") }),
+        };
+        match self.tokens.get(self.pos) {
+            Some((Token::End, _)) => self.pos += 1,
+            // the last command of a block doesn't need a separator
+            Some((Token::CloseBrace, _)) | None => {},
+            Some(_) => return self.error("expected `,` or `;`".to_string()),
+        }
+        Ok(Some((command, line)))
+    }
+}
+
+fn parse_script(script: &str) -> Result<Vec<(Command, usize)>, ScriptError> {
+    let mut parser = CommandParser { tokens: tokenize(script)?, pos: 0 };
+    let mut commands = vec![];
+    while parser.pos < parser.tokens.len() {
+        commands.extend(parser.command()?);
+    }
+    Ok(commands)
+}
+
+pub struct ScriptRunner {
+    dir: PathBuf,
+    cpu: Cpu,
+    output_list: Vec<String>,
+    output_path: Option<PathBuf>,
+    output: Vec<String>,
+    compare: Option<Vec<String>>,
+    mismatch: Option<Outcome>,
+    // the loaded program's, when it was a .asm file
+    info: Option<DebugInfo>,
+    // only kept track of once `enable_coverage` is called
+    coverage: Option<Coverage>,
+    // things the script echoed, for the caller to show
+    pub echoed: Vec<String>,
+}
+
+impl ScriptRunner {
+    // file names in the script are relative to the directory it's in
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            cpu: Cpu::new(vec![]),
+            output_list: vec![],
+            output_path: None,
+            output: vec![],
+            compare: None,
+            mismatch: None,
+            info: None,
+            coverage: None,
+            echoed: vec![],
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn info(&self) -> Option<&DebugInfo> {
+        self.info.as_ref()
+    }
+
+    // counts every instruction the script runs from now on
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(self.cpu.rom().len()));
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    fn write_output(&mut self, line: String) {
+        let line_no = self.output.len();
+        if self.mismatch.is_none() {
+            if let Some(compare) = &self.compare {
+                let expected = compare.get(line_no).cloned().unwrap_or_default();
+                if !lines_match(&expected, &line) {
+                    self.mismatch = Some(Outcome::Mismatch { line: line_no + 1, expected, actual: line.clone() });
+                }
+            }
+        }
+        self.output.push(line);
+    }
+
+    fn columns(&self) -> Vec<OutputColumn<'_>> {
+        self.output_list.iter().filter_map(|column| OutputColumn::parse(column)).collect()
+    }
+
+    fn execute(&mut self, commands: &[(Command, usize)]) -> Result<(), ScriptError> {
+        for (command, line) in commands {
+            if self.mismatch.is_some() {
+                return Ok(());
+            }
+            let error = |message: String| ScriptError { line: *line, message };
+            match command {
+                Command::Load(file) => {
+                    let (rom, info) = load_program_with_debug_info(self.dir.join(file)).map_err(|load_error| error(match load_error {
+                        AssembleError::Io(io_error) => format!("could not load `{file}`: {io_error}"),
+                        AssembleError::Syntax(errors) => format!("could not load `{file}`: {}", errors[0]),
+                    }))?;
+                    if self.coverage.is_some() {
+                        self.coverage = Some(Coverage::new(rom.len()));
+                    }
+                    self.cpu = Cpu::new(rom);
+                    self.info = info;
+                },
+                Command::LoadSnapshot(file) => {
+                    Snapshot::load(self.dir.join(file))
+                        .and_then(|snapshot| snapshot.restore(&mut self.cpu))
+                        .map_err(|snapshot_error| error(format!("could not load `{file}`: {snapshot_error}")))?;
+                },
+                Command::OutputFile(file) => self.output_path = Some(self.dir.join(file)),
+                Command::CompareTo(file) => {
+                    let text = fs::read_to_string(self.dir.join(file)).map_err(|io_error| error(format!("could not read `{file}`: {io_error}")))?;
+                    self.compare = Some(text.lines().map(|line| line.to_string()).collect());
+                },
+                Command::OutputList(columns) => {
+                    self.output_list = columns.clone();
+                    let header = self.columns().iter().map(|column| column.header()).collect::<Vec<_>>();
+                    self.write_output(format!("|{}|", header.join("|")));
+                },
+                Command::Output => {
+                    let values = self.columns().iter().map(|column| column.value(&self.cpu)).collect::<Vec<_>>();
+                    self.write_output(format!("|{}|", values.join("|")));
+                },
+                Command::Set(variable, value) => variable.set(&mut self.cpu, *value).map_err(error)?,
+                Command::Repeat(count, body) => {
+                    for _ in 0..*count {
+                        self.execute(body)?;
+                    }
+                },
+                Command::While(variable, comparison, value, body) => {
+                    while comparison.holds(variable.get(&self.cpu), *value) && self.mismatch.is_none() {
+                        self.execute(body)?;
+                    }
+                },
+                Command::TickTock => {
+                    let pc = self.cpu.pc;
+                    self.cpu.step().map_err(|cpu_error| error(cpu_error.to_string()))?;
+                    if let Some(coverage) = &mut self.coverage {
+                        coverage.record(pc);
+                    }
+                },
+                Command::Echo(text) => self.echoed.push(text.clone()),
+                Command::ClearEcho => self.echoed.clear(),
+            }
+        }
+        Ok(())
+    }
+
+    // runs the whole script, writing the .out file and comparing it against the .cmp file as it goes
+    pub fn run(&mut self, script: &str) -> Result<Outcome, ScriptError> {
+        let commands = parse_script(script)?;
+        let result = self.execute(&commands);
+        if let Some(path) = &self.output_path {
+            let mut text = self.output.join("\n");
+            text.push('\n');
+            fs::write(path, text).map_err(|io_error| ScriptError { line: 0, message: format!("could not write `{}`: {io_error}", path.display()) })?;
+        }
+        result?;
+        Ok(match (self.mismatch.take(), &self.compare) {
+            (Some(mismatch), _) => mismatch,
+            (None, Some(compare)) if compare.len() > self.output.len() => Outcome::Mismatch {
+                line: self.output.len() + 1,
+                expected: compare[self.output.len()].clone(),
+                actual: String::new(),
+            },
+            (None, Some(_)) => Outcome::Passed,
+            (None, None) => Outcome::NoComparison,
+        })
+    }
+}
+
+
+// compares column by column, where a `*` column in the .cmp file matches anything
+fn lines_match(expected: &str, actual: &str) -> bool {
+    let expected = expected.trim_end().split('|').collect::<Vec<_>>();
+    let actual = actual.trim_end().split('|').collect::<Vec<_>>();
+    expected.len() == actual.len()
+        && expected.iter().zip(&actual).all(|(expected, actual)| expected.trim() == "*" || expected.trim() == actual.trim())
+}
+
+// loads and runs a .tst file
+pub fn run_script_file(path: impl AsRef<Path>) -> Result<Outcome, ScriptError> {
+    let path = path.as_ref();
+    let script = fs::read_to_string(path).map_err(|io_error| ScriptError { line: 0, message: format!("could not read `{}`: {io_error}", path.display()) })?;
+    ScriptRunner::new(path.parent().unwrap_or(Path::new("."))).run(&script)
+}
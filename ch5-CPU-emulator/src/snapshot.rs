@@ -0,0 +1,178 @@
+// the whole machine state saved to a file, so a run can pick up from there instead of cycle 0
+use std::{fmt, fs, io, path::Path};
+
+use crate::cpu::RAM_SIZE;
+use crate::Cpu;
+
+const MAGIC: &[u8; 8] = b"HACKSNAP";
+const VERSION: u32 = 2;
+// magic, version, ROM hash, cycles, then PC, A, D and the key, then the RAM, then the device count and
+// per device its start, its name's length and name, and its state's length and state
+const HEADER_SIZE: usize = 8 + 4 + 8 + 8 + 4 * 2;
+
+// an attached device's state, see `Device::save`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceState {
+    pub start: u16,
+    pub name: String,
+    pub state: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    // of the program it was taken from, so it isn't loaded into another one
+    pub rom_hash: u64,
+    pub cycles: u64,
+    pub pc: u16,
+    pub a: u16,
+    pub d: u16,
+    // the key held down, which is also in the RAM but kept apart as it's the host's state, not the program's
+    pub key: u16,
+    pub ram: Vec<u16>,
+    // in the order they were attached
+    pub devices: Vec<DeviceState>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Invalid(String),
+    // the snapshot was taken from a different program than the one loaded
+    WrongProgram,
+    // or with different devices attached
+    WrongDevices(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "{error}"),
+            SnapshotError::Invalid(message) => write!(f, "not a valid snapshot: {message}"),
+            SnapshotError::WrongProgram => write!(f, "the snapshot was taken from a different program"),
+            SnapshotError::WrongDevices(message) => write!(f, "the snapshot doesn't match the devices: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+// FNV-1a over the ROM words, it only has to stay the same between runs and versions
+pub fn rom_hash(rom: &[u16]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in rom.iter().flat_map(|word| word.to_le_bytes()) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl Snapshot {
+    pub fn of(cpu: &Cpu) -> Self {
+        Self {
+            rom_hash: rom_hash(cpu.rom()),
+            cycles: cpu.cycles,
+            pc: cpu.pc,
+            a: cpu.a,
+            d: cpu.d,
+            key: cpu.key(),
+            ram: cpu.ram().to_vec(),
+            devices: cpu.devices().map(|(start, device)| DeviceState { start, name: device.name().to_string(), state: device.save() }).collect(),
+        }
+    }
+
+    // puts the CPU back in this state, as long as it has the same program loaded
+    pub fn restore(&self, cpu: &mut Cpu) -> Result<(), SnapshotError> {
+        if rom_hash(cpu.rom()) != self.rom_hash {
+            return Err(SnapshotError::WrongProgram);
+        }
+        cpu.restore_devices(&self.devices).map_err(SnapshotError::WrongDevices)?;
+        cpu.ram_mut().copy_from_slice(&self.ram);
+        cpu.set_key(self.key);
+        cpu.cycles = self.cycles;
+        cpu.pc = self.pc;
+        cpu.a = self.a;
+        cpu.d = self.d;
+        Ok(())
+    }
+
+    // little endian throughout
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + RAM_SIZE * 2);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.rom_hash.to_le_bytes());
+        bytes.extend_from_slice(&self.cycles.to_le_bytes());
+        for word in [self.pc, self.a, self.d, self.key].iter().chain(&self.ram) {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.devices.len() as u16).to_le_bytes());
+        for device in &self.devices {
+            bytes.extend_from_slice(&device.start.to_le_bytes());
+            bytes.push(device.name.len() as u8);
+            bytes.extend_from_slice(device.name.as_bytes());
+            bytes.extend_from_slice(&(device.state.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&device.state);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        if bytes.get(..8) != Some(MAGIC) {
+            return Err(SnapshotError::Invalid("it doesn't start with `HACKSNAP`".to_string()));
+        }
+        let version = bytes.get(8..12).map(|version| u32::from_le_bytes(version.try_into().unwrap()));
+        if version != Some(VERSION) {
+            return Err(SnapshotError::Invalid(format!("unsupported version {}", version.unwrap_or(0))));
+        }
+        let machine_size = HEADER_SIZE + RAM_SIZE * 2;
+        if bytes.len() < machine_size {
+            return Err(SnapshotError::Invalid(format!("expected at least {machine_size} bytes, got {}", bytes.len())));
+        }
+        let u64_at = |idx: usize| u64::from_le_bytes(bytes[idx..idx + 8].try_into().unwrap());
+        let devices = Self::devices_from_bytes(&bytes[machine_size..]).ok_or_else(|| SnapshotError::Invalid("the devices are cut off".to_string()))?;
+        let words = bytes[28..machine_size].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect::<Vec<_>>();
+        Ok(Self {
+            rom_hash: u64_at(12),
+            cycles: u64_at(20),
+            pc: words[0],
+            a: words[1],
+            d: words[2],
+            key: words[3],
+            ram: words[4..].to_vec(),
+            devices,
+        })
+    }
+
+    // None unless the bytes hold exactly the devices they say
+    fn devices_from_bytes(bytes: &[u8]) -> Option<Vec<DeviceState>> {
+        let mut rest = bytes;
+        let mut take = |count: usize| {
+            let (taken, after) = rest.split_at_checked(count)?;
+            rest = after;
+            Some(taken)
+        };
+        let count = u16::from_le_bytes(take(2)?.try_into().unwrap());
+        let mut devices = vec![];
+        for _ in 0..count {
+            let start = u16::from_le_bytes(take(2)?.try_into().unwrap());
+            let name_length = take(1)?[0] as usize;
+            let name = String::from_utf8(take(name_length)?.to_vec()).ok()?;
+            let state_length = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+            devices.push(DeviceState { start, name, state: take(state_length)?.to_vec() });
+        }
+        rest.is_empty().then_some(devices)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
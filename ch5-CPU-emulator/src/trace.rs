@@ -0,0 +1,199 @@
+// a record of every instruction a program ran, as text or JSON lines, and a way to compare two of them
+use std::fmt;
+
+use assembler::instruction::Instruction;
+use serde_json::{json, Value};
+
+use crate::cpu::MemoryAccess;
+use crate::Cpu;
+
+// one instruction: where it was, what it was, and the registers right after it ran
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    // counting from 1, like `Cpu::cycles` after the instruction
+    pub cycle: u64,
+    pub pc: u16,
+    pub instruction: String,
+    pub a: u16,
+    pub d: u16,
+    // (address, value) when the instruction wrote to RAM
+    pub write: Option<(u16, u16)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Json,
+}
+
+impl TraceEntry {
+    // the entry for the instruction that just ran, given what `Cpu::step_traced` returned for it
+    pub fn new(cpu: &Cpu, access: &MemoryAccess) -> Self {
+        let word = cpu.rom().get(access.pc as usize).copied().unwrap_or(0);
+        Self {
+            cycle: cpu.cycles,
+            pc: access.pc,
+            instruction: Instruction::decode(word).map_or_else(|| format!("{word:016b}"), |instruction| instruction.to_string()),
+            a: cpu.a,
+            d: cpu.d,
+            write: access.write.map(|write| (write.address, write.new)),
+        }
+    }
+
+    // `{"a":0,"cycle":4,"d":256,"instruction":"M=D","pc":3,"write":{"address":0,"value":256}}`, with `"write":null` otherwise
+    pub fn to_json(&self) -> String {
+        let write = self.write.map_or(Value::Null, |(address, value)| json!({ "address": address, "value": value }));
+        json!({
+            "cycle": self.cycle,
+            "pc": self.pc,
+            "instruction": self.instruction,
+            "a": self.a,
+            "d": self.d,
+            "write": write,
+        }).to_string()
+    }
+
+    pub fn from_json(line: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(line).map_err(|error| error.to_string())?;
+        let number = |value: &Value, name: &str| value.get(name).and_then(Value::as_u64).ok_or_else(|| format!("missing or invalid `{name}`"));
+        let word = |value: &Value, name: &str| number(value, name).and_then(|n| u16::try_from(n).map_err(|_| format!("`{name}` doesn't fit in 16 bits")));
+        let write = match value.get("write") {
+            None | Some(Value::Null) => None,
+            Some(write) => Some((word(write, "address")?, word(write, "value")?)),
+        };
+        Ok(Self {
+            cycle: number(&value, "cycle")?,
+            pc: word(&value, "pc")?,
+            instruction: value.get("instruction").and_then(Value::as_str).ok_or("missing or invalid `instruction`")?.to_string(),
+            a: word(&value, "a")?,
+            d: word(&value, "d")?,
+            write,
+        })
+    }
+
+    // the text format, see the `Display` impl
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (cycle, pc, instruction, a, d, write) = match fields[..] {
+            [cycle, pc, instruction, a, d] => (cycle, pc, instruction, a, d, None),
+            [cycle, pc, instruction, a, d, write] => (cycle, pc, instruction, a, d, Some(write)),
+            _ => return Err("expected `cycle PC=n instruction A=n D=n [RAM[n]=n]`".to_string()),
+        };
+        let field = |text: &str, prefix: &str| text.strip_prefix(prefix).and_then(|n| n.parse::<u16>().ok()).ok_or_else(|| format!("expected `{prefix}n`, got `{text}`"));
+        let write = match write {
+            Some(write) => Some(
+                write.strip_prefix("RAM[").and_then(|rest| rest.split_once("]="))
+                    .and_then(|(address, value)| Some((address.parse::<u16>().ok()?, value.parse::<u16>().ok()?)))
+                    .ok_or_else(|| format!("expected `RAM[n]=n`, got `{write}`"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            cycle: cycle.parse().map_err(|_| format!("invalid cycle `{cycle}`"))?,
+            pc: field(pc, "PC=")?,
+            instruction: instruction.to_string(),
+            a: field(a, "A=")?,
+            d: field(d, "D=")?,
+            write,
+        })
+    }
+
+    pub fn format(&self, format: TraceFormat) -> String {
+        match format {
+            TraceFormat::Text => self.to_string(),
+            TraceFormat::Json => self.to_json(),
+        }
+    }
+
+    // same instruction with the same outcome, whatever cycle it ran on
+    fn same_step(&self, other: &Self) -> bool {
+        (self.pc, &self.instruction, self.a, self.d, self.write) == (other.pc, &other.instruction, other.a, other.d, other.write)
+    }
+}
+
+// `4 PC=3 M=D A=0 D=256 RAM[0]=256`, values as unsigned words
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} PC={} {} A={} D={}", self.cycle, self.pc, self.instruction, self.a, self.d)?;
+        if let Some((address, value)) = self.write {
+            write!(f, " RAM[{address}]={value}")?;
+        }
+        Ok(())
+    }
+}
+
+// reads a line of a trace in either format, telling them apart by the `{`; blank lines have no entry
+pub fn parse_trace_line(line: &str, line_no: usize) -> Result<Option<TraceEntry>, TraceError> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let entry = if line.starts_with('{') { TraceEntry::from_json(line) } else { TraceEntry::parse(line) };
+    entry.map(Some).map_err(|message| TraceError { line: line_no + 1, message })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffMode {
+    // the same instruction on the same cycle, for two runs of the same program
+    Cycles,
+    // only the sequence of RAM writes, for programs that compute the same thing in different ways
+    Writes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // None when that trace ended first
+    pub left: Option<TraceEntry>,
+    pub right: Option<TraceEntry>,
+    // the last entries that did agree
+    pub last_match: Option<(TraceEntry, TraceEntry)>,
+}
+
+// walks both traces side by side and returns the first place they disagree, if any
+pub fn first_divergence(
+    left: impl IntoIterator<Item = TraceEntry>,
+    right: impl IntoIterator<Item = TraceEntry>,
+    mode: DiffMode,
+) -> Option<Divergence> {
+    let keep = move |entry: &TraceEntry| mode == DiffMode::Cycles || entry.write.is_some();
+    let mut left = left.into_iter().filter(keep).peekable();
+    let mut right = right.into_iter().filter(keep).peekable();
+    // in cycle mode, a trace that started recording later (say, from a snapshot) lines up with the other by cycle number
+    if mode == DiffMode::Cycles {
+        if let (Some(left_start), Some(right_start)) = (left.peek().map(|entry| entry.cycle), right.peek().map(|entry| entry.cycle)) {
+            while left.next_if(|entry| entry.cycle < right_start).is_some() {}
+            while right.next_if(|entry| entry.cycle < left_start).is_some() {}
+        }
+    }
+
+    let mut last_match = None;
+    loop {
+        let (l, r) = match (left.next(), right.next()) {
+            (None, None) => return None,
+            (Some(l), Some(r)) => (l, r),
+            (left, right) => return Some(Divergence { left, right, last_match }),
+        };
+        let agree = match mode {
+            DiffMode::Cycles => l.cycle == r.cycle && l.same_step(&r),
+            DiffMode::Writes => l.write == r.write,
+        };
+        if !agree {
+            return Some(Divergence { left: Some(l), right: Some(r), last_match });
+        }
+        last_match = Some((l, r));
+    }
+}
@@ -0,0 +1,99 @@
+// watchpoints on RAM, and a trace of every write to it
+use std::fmt;
+
+use crate::cpu::{MemoryAccess, RamWrite};
+use crate::DebugInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // either of them
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchAction {
+    Break,
+    // just report it and keep going
+    Log,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    // inclusive on both ends
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    pub action: WatchAction,
+    // what the user typed, to show it back
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchEvent {
+    Read { address: u16, value: u16 },
+    Write(RamWrite),
+}
+
+impl Watchpoint {
+    pub fn covers(&self, address: u16) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+
+    // what this instruction did that the watchpoint cares about, reads first
+    pub fn events(&self, access: &MemoryAccess) -> Vec<WatchEvent> {
+        let mut events = vec![];
+        if let Some(read) = access.read.filter(|read| self.kind != WatchKind::Write && self.covers(read.address)) {
+            events.push(WatchEvent::Read { address: read.address, value: read.value });
+        }
+        if let Some(write) = access.write.filter(|write| self.kind != WatchKind::Read && self.covers(write.address)) {
+            events.push(WatchEvent::Write(write));
+        }
+        events
+    }
+}
+
+// a watchpoint that triggered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: usize,
+    // the cycle the instruction ran on, counting from 1
+    pub cycle: u64,
+    pub pc: u16,
+    pub event: WatchEvent,
+}
+
+// `RAM[0] (SP)`, or just `RAM[300]` when there's no symbol for it
+pub fn ram_label(info: Option<&DebugInfo>, address: u16) -> String {
+    match info.and_then(|info| info.ram_name(address)) {
+        Some(name) => format!("RAM[{address}] ({name})"),
+        None => format!("RAM[{address}]"),
+    }
+}
+
+impl WatchHit {
+    // `watchpoint 1: ROM[12] wrote RAM[0] (SP): 256 -> 257 (cycle 40)`
+    pub fn describe(&self, info: Option<&DebugInfo>) -> String {
+        let what = match self.event {
+            WatchEvent::Read { address, value } => format!("read {}: {}", ram_label(info, address), value as i16),
+            WatchEvent::Write(write) => format!("wrote {}: {} -> {}", ram_label(info, write.address), write.old as i16, write.new as i16),
+        };
+        format!("watchpoint {}: ROM[{}] {what} (cycle {})", self.id, self.pc, self.cycle)
+    }
+}
+
+// one line of the write trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteRecord {
+    pub cycle: u64,
+    pub pc: u16,
+    pub write: RamWrite,
+}
+
+// `cycle pc address old new`, whitespace separated with values as unsigned words so it's easy to diff and grep
+impl fmt::Display for WriteRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {} {}", self.cycle, self.pc, self.write.address, self.write.old, self.write.new)
+    }
+}
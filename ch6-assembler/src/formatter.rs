@@ -0,0 +1,126 @@
+use crate::lexer::SyntaxError;
+use crate::parser::{parse_source, SourceLine, Statement};
+
+const INDENT: &str = "    ";
+// minimum gap between the code and an aligned comment
+const COMMENT_GAP: usize = 2;
+
+fn format_comment(text: &str) -> String {
+    // keep `////` banners as they are, everything else gets a single space after `//`
+    if text.is_empty() || text.starts_with('/') {
+        format!("//{text}")
+    } else {
+        format!("// {text}")
+    }
+}
+
+fn format_statement(statement: &Statement) -> String {
+    match statement {
+        Statement::Label(_) => statement.to_string(),
+        _ => format!("{INDENT}{statement}"),
+    }
+}
+
+// a comment on its own line is indented like the code right after it,
+// and stays flush left when there's no code after it in the same block
+fn standalone_indent(block: &[SourceLine]) -> &'static str {
+    let next = block.iter().find_map(|line| line.statement.as_ref());
+    match next {
+        Some((Statement::Label(_), _)) | None => "",
+        Some(_) => INDENT,
+    }
+}
+
+// labels flush left, instructions indented, inline comments aligned inside each block of consecutive lines,
+// and runs of blank lines collapsed into one
+pub fn format_lines(lines: &[SourceLine]) -> String {
+    let code: Vec<Option<String>> = lines.iter()
+        .map(|line| line.statement.as_ref().map(|(statement, _)| format_statement(statement)))
+        .collect();
+    let is_blank = |idx: usize| lines[idx].statement.is_none() && lines[idx].comment.is_none();
+
+    let mut out = String::new();
+    let mut idx = 0;
+    while idx < lines.len() {
+        if is_blank(idx) {
+            if !out.is_empty() && !out.ends_with("\n\n") {
+                out.push('\n');
+            }
+            idx += 1;
+            continue;
+        }
+        let block_end = (idx..lines.len()).find(|&i| is_blank(i)).unwrap_or(lines.len());
+        let comment_column = (idx..block_end)
+            .filter(|&i| lines[i].comment.is_some())
+            .filter_map(|i| code[i].as_ref().map(|code| code.len()))
+            .max()
+            .map(|width| width + COMMENT_GAP);
+
+        for i in idx..block_end {
+            let line = match (&code[i], &lines[i].comment) {
+                (Some(code), Some((comment, _))) => {
+                    format!("{code:width$}{}", format_comment(comment), width = comment_column.unwrap())
+                },
+                (Some(code), None) => code.clone(),
+                (None, Some((comment, _))) => format!("{}{}", standalone_indent(&lines[i..block_end]), format_comment(comment)),
+                (None, None) => unreachable!("blank lines end the block"),
+            };
+            out.push_str(&line);
+            out.push('\n');
+        }
+        idx = block_end;
+    }
+    if out.ends_with("\n\n") {
+        out.pop();
+    }
+    out
+}
+
+pub fn format_source(source: &str) -> Result<String, Vec<SyntaxError>> {
+    let (lines, errors) = parse_source(source);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(format_lines(&lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = "//// header\r\n   (LOOP)   // top\n@i\nM = A+D // commutative\n\n\n\tD=D&A\n   // next\n@LOOP\n0;JMP // back\n\n";
+
+    fn statements(source: &str) -> Vec<Statement> {
+        let (lines, errors) = parse_source(source);
+        assert!(errors.is_empty(), "{errors:?}");
+        lines.into_iter().filter_map(|line| line.statement).map(|(statement, _)| statement).collect()
+    }
+
+    #[test]
+    fn canonical_layout() {
+        let expected = "\
+//// header
+(LOOP)     // top
+    @i
+    M=D+A  // commutative
+
+    D=D&A
+    // next
+    @LOOP
+    0;JMP  // back
+";
+        assert_eq!(format_source(MESSY).unwrap(), expected);
+    }
+
+    #[test]
+    fn commutative_comps_are_normalised() {
+        assert_eq!(format_source("A=A+D\nD=M&D\nAM=M|D;JNE\n").unwrap(), "    A=D+A\n    D=D&M\n    AM=D|M;JNE\n");
+    }
+
+    #[test]
+    fn parse_format_parse_is_idempotent() {
+        let formatted = format_source(MESSY).unwrap();
+        assert_eq!(statements(&formatted), statements(MESSY));
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+}
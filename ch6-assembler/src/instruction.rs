@@ -0,0 +1,336 @@
+use std::fmt;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SaveCompTo {
+    pub a: bool,
+    pub d: bool,
+    pub m: bool,
+}
+
+impl SaveCompTo {
+    // any combination of A, D and M, each at most once (`MD`, `AM`, `AMD`...)
+    pub fn parse(string: &str) -> Option<Self> {
+        let mut save_to = Self::default();
+        for c in string.chars() {
+            let flag = match c {
+                'A' => &mut save_to.a,
+                'D' => &mut save_to.d,
+                'M' => &mut save_to.m,
+                _ => return None,
+            };
+            if *flag {
+                return None;
+            }
+            *flag = true;
+        }
+        Some(save_to)
+    }
+}
+
+impl fmt::Display for SaveCompTo {
+    // same order as the book's table: `AM`, `AD`, `MD`, `AMD`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.a { f.write_str("A")?; }
+        if self.m { f.write_str("M")?; }
+        if self.d { f.write_str("D")?; }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JumpIf {
+    #[default]
+    Null,
+    GT,
+    EQ,
+    GE,
+    LT,
+    NE,
+    LE,
+    Jmp,
+}
+
+impl JumpIf {
+    pub const ALL: [JumpIf; 8] = [
+        JumpIf::Null, JumpIf::GT, JumpIf::EQ, JumpIf::GE, JumpIf::LT, JumpIf::NE, JumpIf::LE, JumpIf::Jmp,
+    ];
+
+    pub fn parse(string: &str) -> Option<Self> {
+        match string {
+            "JGT" => Some(JumpIf::GT),
+            "JEQ" => Some(JumpIf::EQ),
+            "JGE" => Some(JumpIf::GE),
+            "JLT" => Some(JumpIf::LT),
+            "JNE" => Some(JumpIf::NE),
+            "JLE" => Some(JumpIf::LE),
+            "JMP" => Some(JumpIf::Jmp),
+            _ => None,
+        }
+    }
+
+    pub fn bits(&self) -> u16 {
+        match self {
+            JumpIf::Null => 0b000,
+            JumpIf::GT => 0b001,
+            JumpIf::EQ => 0b010,
+            JumpIf::GE => 0b011,
+            JumpIf::LT => 0b100,
+            JumpIf::NE => 0b101,
+            JumpIf::LE => 0b110,
+            JumpIf::Jmp => 0b111,
+        }
+    }
+
+    // whether the jump is taken for a given ALU output
+    pub fn should_jump(&self, out: u16) -> bool {
+        let out = out as i16;
+        match self {
+            JumpIf::Null => false,
+            JumpIf::GT => out > 0,
+            JumpIf::EQ => out == 0,
+            JumpIf::GE => out >= 0,
+            JumpIf::LT => out < 0,
+            JumpIf::NE => out != 0,
+            JumpIf::LE => out <= 0,
+            JumpIf::Jmp => true,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JumpIf::Null => "",
+            JumpIf::GT => "JGT",
+            JumpIf::EQ => "JEQ",
+            JumpIf::GE => "JGE",
+            JumpIf::LT => "JLT",
+            JumpIf::NE => "JNE",
+            JumpIf::LE => "JLE",
+            JumpIf::Jmp => "JMP",
+        }
+    }
+}
+
+// the Hack ALU: `d` goes into its x input and A/M into y, `bits` are zx nx zy ny f no
+#[inline]
+pub fn alu(bits: u16, d: u16, x: u16) -> u16 {
+    let flag = |bit: u16| bits >> bit & 1 == 1;
+    let mut left = if flag(5) {0} else {d};
+    if flag(4) { left = !left; }
+    let mut right = if flag(3) {0} else {x};
+    if flag(2) { right = !right; }
+    let out = if flag(1) { left.wrapping_add(right) } else { left & right };
+    if flag(0) { !out } else { out }
+}
+
+// X here will correspond to A or M since we can choose between either
+// this also means that operations involving A AND M are forbidden
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Comp {
+    #[default]
+    Zero,
+    One,
+    NegOne,
+    D,
+    X,
+    NotD,
+    NotX,
+    NegD,
+    NegX,
+    DPlusOne,
+    XPlusOne,
+    DMinusOne,
+    XMinusOne,
+    SumDAndX,
+    DMinusX,
+    XMinusD,
+    DAndX,
+    DOrX,
+}
+
+impl Comp {
+    pub const ALL: [Comp; 18] = [
+        Comp::Zero, Comp::One, Comp::NegOne, Comp::D, Comp::X, Comp::NotD, Comp::NotX, Comp::NegD, Comp::NegX,
+        Comp::DPlusOne, Comp::XPlusOne, Comp::DMinusOne, Comp::XMinusOne,
+        Comp::SumDAndX, Comp::DMinusX, Comp::XMinusD, Comp::DAndX, Comp::DOrX,
+    ];
+
+    // whether the computation reads A/M at all, i.e. if `switch_a_for_m` makes a difference
+    pub fn uses_x(&self) -> bool {
+        !matches!(self, Comp::Zero | Comp::One | Comp::NegOne | Comp::D | Comp::NotD | Comp::NegD | Comp::DPlusOne | Comp::DMinusOne)
+    }
+
+    // returns the computation along with whether it reads M instead of A
+    pub fn parse(string: &str) -> Option<(Self, bool)> {
+        let comp = match string {
+            "0" => (Comp::Zero, false),
+            "1" => (Comp::One, false),
+            "-1" => (Comp::NegOne, false),
+            "D" => (Comp::D, false),
+            "A" => (Comp::X, false),
+            "M" => (Comp::X, true),
+            "!D" => (Comp::NotD, false),
+            "!A" => (Comp::NotX, false),
+            "!M" => (Comp::NotX, true),
+            "-D" => (Comp::NegD, false),
+            "-A" => (Comp::NegX, false),
+            "-M" => (Comp::NegX, true),
+            "D+1" | "1+D" => (Comp::DPlusOne, false),
+            "A+1" | "1+A" => (Comp::XPlusOne, false),
+            "M+1" | "1+M" => (Comp::XPlusOne, true),
+            "D-1" => (Comp::DMinusOne, false),
+            "A-1" => (Comp::XMinusOne, false),
+            "M-1" => (Comp::XMinusOne, true),
+            "D+A" | "A+D" => (Comp::SumDAndX, false),
+            "D+M" | "M+D" => (Comp::SumDAndX, true),
+            "D-A" => (Comp::DMinusX, false),
+            "D-M" => (Comp::DMinusX, true),
+            "A-D" => (Comp::XMinusD, false),
+            "M-D" => (Comp::XMinusD, true),
+            "D&A" | "A&D" => (Comp::DAndX, false),
+            "D&M" | "M&D" => (Comp::DAndX, true),
+            "D|A" | "A|D" => (Comp::DOrX, false),
+            "D|M" | "M|D" => (Comp::DOrX, true),
+            _ => return None,
+        };
+        Some(comp)
+    }
+
+    // the 6 ALU control bits: zx nx zy ny f no
+    pub fn bits(&self) -> u16 {
+        match self {
+            Comp::Zero => 0b101010,
+            Comp::One => 0b111111,
+            Comp::NegOne => 0b111010,
+            Comp::D => 0b001100,
+            Comp::X => 0b110000,
+            Comp::NotD => 0b001101,
+            Comp::NotX => 0b110001,
+            Comp::NegD => 0b001111,
+            Comp::NegX => 0b110011,
+            Comp::DPlusOne => 0b011111,
+            Comp::XPlusOne => 0b110111,
+            Comp::DMinusOne => 0b001110,
+            Comp::XMinusOne => 0b110010,
+            Comp::SumDAndX => 0b000010,
+            Comp::DMinusX => 0b010011,
+            Comp::XMinusD => 0b000111,
+            Comp::DAndX => 0b000000,
+            Comp::DOrX => 0b010101,
+        }
+    }
+
+    pub fn from_bits(bits: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|comp| comp.bits() == bits)
+    }
+
+    // runs the computation through the ALU, with `x` being either A or M
+    #[inline]
+    pub fn compute(&self, d: u16, x: u16) -> u16 {
+        alu(self.bits(), d, x)
+    }
+
+    // the canonical mnemonic, which is also what `parse` accepts first for each computation
+    pub fn as_str(&self, switch_a_for_m: bool) -> &'static str {
+        let (with_a, with_m) = match self {
+            Comp::Zero => ("0", "0"),
+            Comp::One => ("1", "1"),
+            Comp::NegOne => ("-1", "-1"),
+            Comp::D => ("D", "D"),
+            Comp::X => ("A", "M"),
+            Comp::NotD => ("!D", "!D"),
+            Comp::NotX => ("!A", "!M"),
+            Comp::NegD => ("-D", "-D"),
+            Comp::NegX => ("-A", "-M"),
+            Comp::DPlusOne => ("D+1", "D+1"),
+            Comp::XPlusOne => ("A+1", "M+1"),
+            Comp::DMinusOne => ("D-1", "D-1"),
+            Comp::XMinusOne => ("A-1", "M-1"),
+            Comp::SumDAndX => ("D+A", "D+M"),
+            Comp::DMinusX => ("D-A", "D-M"),
+            Comp::XMinusD => ("A-D", "M-D"),
+            Comp::DAndX => ("D&A", "D&M"),
+            Comp::DOrX => ("D|A", "D|M"),
+        };
+        if switch_a_for_m { with_m } else { with_a }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CInstr {
+    pub switch_a_for_m: bool,
+    pub comp: Comp,
+    pub save_comp_to: SaveCompTo,
+    pub jump_if: JumpIf,
+}
+
+impl CInstr {
+    pub fn encode(&self) -> u16 {
+        let dest = (self.save_comp_to.a as u16) << 2 | (self.save_comp_to.d as u16) << 1 | self.save_comp_to.m as u16;
+        0b111 << 13 | (self.switch_a_for_m as u16) << 12 | self.comp.bits() << 6 | dest << 3 | self.jump_if.bits()
+    }
+
+    // None for words that aren't C instructions or whose comp bits don't match a known computation
+    pub fn decode(word: u16) -> Option<Self> {
+        if word >> 13 != 0b111 {
+            return None;
+        }
+        let jump_bits = word & 0b111;
+        Some(Self {
+            switch_a_for_m: word >> 12 & 1 == 1,
+            comp: Comp::from_bits(word >> 6 & 0b111111)?,
+            save_comp_to: SaveCompTo { a: word >> 5 & 1 == 1, d: word >> 4 & 1 == 1, m: word >> 3 & 1 == 1 },
+            jump_if: JumpIf::ALL.into_iter().find(|jump| jump.bits() == jump_bits).unwrap(),
+        })
+    }
+}
+
+impl fmt::Display for CInstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let save_comp_to = self.save_comp_to.to_string();
+        if !save_comp_to.is_empty() {
+            write!(f, "{save_comp_to}=")?;
+        }
+        f.write_str(self.comp.as_str(self.switch_a_for_m))?;
+        if self.jump_if != JumpIf::Null {
+            write!(f, ";{}", self.jump_if.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+// A instructions only have 15 bits for their value
+pub const MAX_A_VALUE: u16 = 0x7fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    // the value loaded into A, only 15 bits wide
+    A(u16),
+    C(CInstr),
+}
+
+impl Instruction {
+    pub fn encode(&self) -> u16 {
+        match self {
+            // the parser and the symbol table keep the value in range
+            Instruction::A(value) => *value,
+            Instruction::C(c_instr) => c_instr.encode(),
+        }
+    }
+
+    pub fn decode(word: u16) -> Option<Self> {
+        if word >> 15 == 0 {
+            Some(Instruction::A(word))
+        } else {
+            CInstr::decode(word).map(Instruction::C)
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::A(value) => write!(f, "@{value}"),
+            Instruction::C(c_instr) => write!(f, "{c_instr}"),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+
+// position of a token in the source: 0-based line, and the byte range inside that line
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    // `(NAME)`, holds NAME
+    LabelDecl(String),
+    // `@value`, holds value
+    AInstr(String),
+    // the 3 parts of a C instruction `dest=comp;jump`, with whitespace removed
+    Dest(String),
+    Comp(String),
+    Jump(String),
+    // `// text`, holds text
+    Comment(String),
+    // `.name args`, holds the whole thing without the dot
+    Directive(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line + 1, self.span.start + 1, self.message)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | ':')
+}
+
+// where the code part of a line stops, i.e. the start of a `//` comment or the end of the line
+fn code_end(line: &str) -> usize {
+    line.find("//").unwrap_or(line.len())
+}
+
+pub fn lex_line(line: &str, line_no: usize) -> Result<Vec<Token>, SyntaxError> {
+    // CRLF files come in with the `\r` still attached
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let span = |start: usize, end: usize| Span { line: line_no, start, end };
+    let error = |start: usize, end: usize, message: String| SyntaxError { span: span(start, end), message };
+
+    let mut tokens = vec![];
+    let code_end = code_end(line);
+    let code = &line[..code_end];
+    let start = code.len() - code.trim_start().len();
+    let end = code.trim_end().len();
+
+    if start < end {
+        let text = &code[start..end];
+        match text.chars().next().unwrap() {
+            '(' => {
+                let Some(close) = text.find(')') else {
+                    return Err(error(start, end, "label is missing a closing `)`".to_string()));
+                };
+                if close + 1 != text.len() {
+                    return Err(error(start + close + 1, end, "unexpected text after label".to_string()));
+                }
+                let name = text[1..close].trim();
+                if name.is_empty() || !name.chars().all(is_symbol_char) {
+                    return Err(error(start, end, format!("invalid label name `{name}`")));
+                }
+                tokens.push(Token { kind: TokenKind::LabelDecl(name.to_string()), span: span(start, end) });
+            },
+            '@' => {
+                let value = &text[1..];
+                if value.is_empty() {
+                    return Err(error(start, end, "`@` is missing an address or symbol".to_string()));
+                }
+                if let Some((idx, c)) = value.char_indices().find(|&(_, c)| !is_symbol_char(c)) {
+                    return Err(error(start + 1 + idx, end, format!("unexpected `{c}` in A instruction")));
+                }
+                tokens.push(Token { kind: TokenKind::AInstr(value.to_string()), span: span(start, end) });
+            },
+            '.' => {
+                tokens.push(Token { kind: TokenKind::Directive(text[1..].to_string()), span: span(start, end) });
+            },
+            _ => lex_c_instr(text, start, line_no, &mut tokens)?,
+        }
+    }
+
+    if code_end < line.len() {
+        let comment = line[code_end + 2..].trim();
+        tokens.push(Token { kind: TokenKind::Comment(comment.to_string()), span: span(code_end, line.trim_end().len()) });
+    }
+    Ok(tokens)
+}
+
+// splits `dest=comp;jump` into its parts, allowing whitespace anywhere in between
+fn lex_c_instr(text: &str, offset: usize, line_no: usize, tokens: &mut Vec<Token>) -> Result<(), SyntaxError> {
+    // (part, start, end) for each part we've seen so far
+    let mut parts: Vec<(String, usize, usize)> = vec![(String::new(), offset, offset)];
+    let mut separators = vec![];
+    for (idx, c) in text.char_indices() {
+        let pos = offset + idx;
+        match c {
+            ' ' | '\t' => {},
+            '=' | ';' => {
+                separators.push((c, pos));
+                parts.push((String::new(), pos + 1, pos + 1));
+            },
+            _ => {
+                let part = parts.last_mut().unwrap();
+                if part.0.is_empty() {
+                    part.1 = pos;
+                }
+                part.0.push(c);
+                part.2 = pos + c.len_utf8();
+            },
+        }
+    }
+
+    let error = |start: usize, end: usize, message: &str| SyntaxError {
+        span: Span { line: line_no, start, end },
+        message: message.to_string(),
+    };
+    let kinds: Vec<fn(String) -> TokenKind> = match separators.as_slice() {
+        [] => vec![TokenKind::Comp],
+        [('=', _)] => vec![TokenKind::Dest, TokenKind::Comp],
+        [(';', _)] => vec![TokenKind::Comp, TokenKind::Jump],
+        [('=', _), (';', _)] => vec![TokenKind::Dest, TokenKind::Comp, TokenKind::Jump],
+        _ => {
+            let (_, pos) = separators.last().unwrap();
+            return Err(error(*pos, *pos + 1, "unexpected separator in C instruction"));
+        },
+    };
+    for (kind, (part, start, end)) in kinds.into_iter().zip(parts) {
+        if part.is_empty() {
+            return Err(error(start, end, "empty field in C instruction"));
+        }
+        tokens.push(Token { kind: kind(part), span: Span { line: line_no, start, end } });
+    }
+    Ok(())
+}
@@ -0,0 +1,212 @@
+pub mod formatter;
+pub mod instruction;
+pub mod lexer;
+pub mod lsp;
+pub mod parser;
+pub mod symbols;
+
+use std::io::{self, BufRead, Write};
+
+use crate::instruction::Instruction;
+use crate::lexer::SyntaxError;
+use crate::parser::{parse_line, parse_source, SourceLine, Statement};
+use crate::symbols::SymbolTable;
+
+#[derive(Debug)]
+pub enum AssembleError {
+    Io(io::Error),
+    Syntax(Vec<SyntaxError>),
+}
+
+impl From<io::Error> for AssembleError {
+    fn from(error: io::Error) -> Self {
+        AssembleError::Io(error)
+    }
+}
+
+// the word for a single line, if it holds an instruction
+fn encode_line(line: &SourceLine, symbols: &mut SymbolTable) -> Result<Option<u16>, SyntaxError> {
+    match &line.statement {
+        Some((Statement::AInstr(address), span)) => match symbols.resolve_address(address) {
+            Ok(value) => Ok(Some(Instruction::A(value).encode())),
+            Err(message) => Err(SyntaxError { span: *span, message }),
+        },
+        Some((Statement::CInstr(c_instr), _)) => Ok(Some(Instruction::C(*c_instr).encode())),
+        Some((Statement::Label(_), _)) | None => Ok(None),
+    }
+}
+
+// second pass: turn every instruction into its 16-bit word
+pub fn assemble_lines(lines: &[SourceLine]) -> Result<Vec<u16>, Vec<SyntaxError>> {
+    assemble_program_lines(lines).map(|program| program.words)
+}
+
+// calls `f` on each parsed line without ever holding more than one line in memory
+fn for_each_line(mut input: impl BufRead, mut f: impl FnMut(Result<SourceLine, SyntaxError>)) -> io::Result<()> {
+    let mut buf = String::new();
+    let mut line_no = 0;
+    while input.read_line(&mut buf)? > 0 {
+        let line = buf.strip_suffix('\n').unwrap_or(&buf);
+        f(parse_line(line, line_no));
+        buf.clear();
+        line_no += 1;
+    }
+    Ok(())
+}
+
+// streaming first pass: only the symbol table is kept around
+pub fn first_pass(input: impl BufRead) -> Result<SymbolTable, AssembleError> {
+    let mut symbols = SymbolTable::default();
+    let mut errors = vec![];
+    let mut rom_address = 0;
+    for_each_line(input, |line| {
+        if let Err(error) = line.and_then(|line| symbols.scan_line(&line, &mut rom_address)) {
+            errors.push(error);
+        }
+    })?;
+    if !errors.is_empty() {
+        return Err(AssembleError::Syntax(errors));
+    }
+    Ok(symbols)
+}
+
+// streaming second pass: each word goes straight to `out` in the .hack text format
+pub fn second_pass(input: impl BufRead, symbols: &mut SymbolTable, out: &mut impl Write) -> Result<(), AssembleError> {
+    let mut result = Ok(());
+    for_each_line(input, |line| {
+        if result.is_err() {
+            return;
+        }
+        result = match line {
+            Ok(line) => match encode_line(&line, symbols) {
+                Ok(Some(word)) => writeln!(out, "{word:016b}").map_err(AssembleError::Io),
+                Ok(None) => Ok(()),
+                Err(error) => Err(AssembleError::Syntax(vec![error])),
+            },
+            // the first pass already went through the same lines, so this only happens if the file changed in between
+            Err(error) => Err(AssembleError::Syntax(vec![error])),
+        };
+    })?;
+    result
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u16>, Vec<SyntaxError>> {
+    assemble_program(source).map(|program| program.words)
+}
+
+// an assembled program along with what debuggers need to map it back to the source
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub words: Vec<u16>,
+    pub symbols: SymbolTable,
+    // ROM address -> 0-based source line of the instruction
+    pub source_lines: Vec<usize>,
+}
+
+pub fn assemble_program(source: &str) -> Result<Program, Vec<SyntaxError>> {
+    let (lines, errors) = parse_source(source);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    assemble_program_lines(&lines)
+}
+
+fn assemble_program_lines(lines: &[SourceLine]) -> Result<Program, Vec<SyntaxError>> {
+    let (mut symbols, mut errors) = SymbolTable::from_lines(lines);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut words = vec![];
+    let mut source_lines = vec![];
+    for line in lines {
+        match encode_line(line, &mut symbols) {
+            Ok(Some(word)) => {
+                words.push(word);
+                source_lines.push(line.line);
+            },
+            Ok(None) => {},
+            Err(error) => errors.push(error),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Program { words, symbols, source_lines })
+}
+
+// the .hack text format: one word per line, written out as 16 binary digits
+pub fn write_hack(words: &[u16], out: &mut impl Write) -> io::Result<()> {
+    for word in words {
+        writeln!(out, "{word:016b}")?;
+    }
+    Ok(())
+}
+
+pub fn read_hack(text: &str) -> Result<Vec<u16>, SyntaxError> {
+    let mut words = vec![];
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match u16::from_str_radix(line, 2) {
+            Ok(word) if line.len() == 16 => words.push(word),
+            _ => return Err(SyntaxError {
+                span: lexer::Span { line: line_no, start: 0, end: line.len() },
+                message: format!("`{line}` is not a 16-bit binary word"),
+            }),
+        }
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::ROM_SIZE;
+
+    fn instructions(count: usize) -> Vec<String> {
+        vec!["D=0".to_string(); count]
+    }
+
+    #[test]
+    fn variables_fill_the_ram_up_to_the_last_a_value() {
+        // variables start at RAM[16], so 32752 of them fit
+        let fits = (0..32752).map(|idx| format!("@v{idx}")).collect::<Vec<_>>();
+        let words = assemble(&fits.join("\n")).unwrap();
+        assert_eq!(words.last(), Some(&instruction::MAX_A_VALUE));
+
+        let source = fits.iter().cloned().chain(["@one_more".to_string()]).collect::<Vec<_>>().join("\n");
+        let errors = assemble(&source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span.line, 32752);
+        assert!(errors[0].message.contains("no room for variable `one_more`"), "{}", errors[0].message);
+    }
+
+    #[test]
+    fn programs_fill_the_rom() {
+        let full = instructions(ROM_SIZE as usize);
+        assert_eq!(assemble(&full.join("\n")).unwrap().len(), ROM_SIZE as usize);
+
+        // only the first instruction that doesn't fit gets reported
+        let errors = assemble(&instructions(ROM_SIZE as usize + 2).join("\n")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span.line, ROM_SIZE as usize);
+        assert!(errors[0].message.contains("doesn't fit"), "{}", errors[0].message);
+    }
+
+    #[test]
+    fn labels_past_a_full_rom_cant_be_referenced() {
+        let mut source = instructions(ROM_SIZE as usize - 1);
+        source.extend(["@END".to_string(), "(END)".to_string()]);
+        let errors = assemble(&source.join("\n")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span.line, ROM_SIZE as usize - 1);
+        assert!(errors[0].message.contains("`END` points past the end"), "{}", errors[0].message);
+
+        // nothing is wrong with it as long as no one jumps there
+        let mut source = instructions(ROM_SIZE as usize);
+        source.push("(END)".to_string());
+        assert!(assemble(&source.join("\n")).is_ok());
+    }
+}
@@ -0,0 +1,434 @@
+// a small language server over stdio, speaking just enough LSP for diagnostics,
+// go-to-definition, references, hover, completion and renaming labels
+use std::{
+    collections::HashMap,
+    io::{self, prelude::*},
+};
+
+use serde_json::{json, Value};
+
+use crate::instruction::{Comp, Instruction, JumpIf, MAX_A_VALUE};
+use crate::lexer::{Span, SyntaxError};
+use crate::parser::{parse_source, Address, SourceLine, Statement};
+use crate::symbols::{Symbol, SymbolKind, SymbolTable, ROM_SIZE};
+
+// a place in the document where a symbol shows up, either declared as a label or used by an A instruction
+#[derive(Debug, Clone)]
+struct Occurrence {
+    name: String,
+    // just the symbol name, without the `@` or parens around it
+    span: Span,
+    is_declaration: bool,
+}
+
+#[derive(Debug)]
+struct Document {
+    text: String,
+    lines: Vec<SourceLine>,
+    errors: Vec<SyntaxError>,
+    symbols: SymbolTable,
+    occurrences: Vec<Occurrence>,
+    // line -> ROM address, for the lines that hold an instruction
+    rom_addresses: HashMap<usize, u16>,
+}
+
+impl Document {
+    fn new(text: String) -> Self {
+        let (lines, mut errors) = parse_source(&text);
+        let (mut symbols, label_errors) = SymbolTable::from_lines(&lines);
+        errors.extend(label_errors);
+
+        let source_lines = text.lines().collect::<Vec<_>>();
+        let mut occurrences = vec![];
+        let mut rom_addresses = HashMap::new();
+        let mut rom_address: u16 = 0;
+        for line in &lines {
+            match &line.statement {
+                Some((Statement::Label(name), span)) => {
+                    // the name might have whitespace around it inside the parens
+                    let offset = source_lines[line.line][span.start..span.end].find(name.as_str()).unwrap_or(1);
+                    let start = span.start + offset;
+                    occurrences.push(Occurrence {
+                        name: name.clone(),
+                        span: Span { start, end: start + name.len(), ..*span },
+                        is_declaration: true,
+                    });
+                },
+                Some((Statement::AInstr(Address::Symbol(name)), span)) => {
+                    // resolving in order allocates variables exactly like the assembler does
+                    if let Err(message) = symbols.resolve(name) {
+                        errors.push(SyntaxError { span: *span, message });
+                    }
+                    occurrences.push(Occurrence {
+                        name: name.clone(),
+                        span: Span { start: span.start + 1, ..*span },
+                        is_declaration: false,
+                    });
+                },
+                _ => {},
+            }
+            if line.is_instruction() {
+                // past the end of the ROM there's no address to show, `from_lines` already reported it
+                if rom_address < ROM_SIZE {
+                    rom_addresses.insert(line.line, rom_address);
+                }
+                rom_address = rom_address.saturating_add(1);
+            }
+        }
+        Self { text, lines, errors, symbols, occurrences, rom_addresses }
+    }
+
+    fn line_text(&self, line: usize) -> &str {
+        self.text.lines().nth(line).unwrap_or("")
+    }
+
+    // LSP positions count UTF-16 code units while spans count bytes, these convert between the two;
+    // a position inside a character or past the end of the line lands on the next character boundary
+    fn byte_offset(&self, line: usize, character: usize) -> usize {
+        let line_text = self.line_text(line);
+        let mut utf16 = 0;
+        for (byte, c) in line_text.char_indices() {
+            if utf16 >= character {
+                return byte;
+            }
+            utf16 += c.len_utf16();
+        }
+        line_text.len()
+    }
+
+    fn utf16_offset(&self, line: usize, byte: usize) -> usize {
+        self.line_text(line).char_indices().take_while(|(start, _)| *start < byte).map(|(_, c)| c.len_utf16()).sum()
+    }
+
+    fn range(&self, span: &Span) -> Value {
+        json!({
+            "start": {"line": span.line, "character": self.utf16_offset(span.line, span.start)},
+            "end": {"line": span.line, "character": self.utf16_offset(span.line, span.end)},
+        })
+    }
+
+    fn location(&self, uri: &str, span: &Span) -> Value {
+        json!({"uri": uri, "range": self.range(span)})
+    }
+
+    fn occurrence_at(&self, line: usize, character: usize) -> Option<&Occurrence> {
+        self.occurrences.iter()
+            .find(|occ| occ.span.line == line && occ.span.start <= character && character <= occ.span.end)
+    }
+
+    fn occurrences_of<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Occurrence> {
+        self.occurrences.iter().filter(move |occ| occ.name == name)
+    }
+
+    // where a symbol is "defined": its label, or the first A instruction using it for variables
+    fn definition_of<'a>(&'a self, name: &'a str) -> Option<&'a Occurrence> {
+        self.occurrences_of(name).find(|occ| occ.is_declaration).or_else(|| self.occurrences_of(name).next())
+    }
+}
+
+fn describe_symbol(name: &str, symbol: &Symbol) -> String {
+    match symbol.kind {
+        SymbolKind::Label => format!("label `{name}`: ROM[{}]", symbol.address),
+        SymbolKind::Variable => format!("variable `{name}`: RAM[{}]", symbol.address),
+        SymbolKind::Predefined => format!("predefined `{name}`: RAM[{}]", symbol.address),
+    }
+}
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+
+// what a request gets back instead of a result
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+impl From<String> for ResponseError {
+    fn from(message: String) -> Self {
+        Self { code: INTERNAL_ERROR, message }
+    }
+}
+
+impl From<&str> for ResponseError {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<String, Document>,
+    shutdown_requested: bool,
+}
+
+impl Server {
+    fn publish_diagnostics(&self, uri: &str, out: &mut impl Write) -> io::Result<()> {
+        let diagnostics = self.documents.get(uri).map_or(vec![], |doc| {
+            doc.errors.iter()
+                .map(|error| json!({"range": doc.range(&error.span), "severity": 1, "source": "hack-asm", "message": error.message}))
+                .collect()
+        });
+        write_message(out, &json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        }))
+    }
+
+    fn hover(&self, doc: &Document, line: usize, character: usize) -> Value {
+        let mut contents = vec![];
+        if let Some(occ) = doc.occurrence_at(line, character) {
+            if let Some(symbol) = doc.symbols.get(&occ.name) {
+                contents.push(describe_symbol(&occ.name, &symbol));
+            }
+        }
+        let statement = doc.lines.iter().find(|l| l.line == line).and_then(|l| l.statement.as_ref());
+        if let (Some((statement, _)), Some(rom_address)) = (statement, doc.rom_addresses.get(&line)) {
+            let instruction = match statement {
+                Statement::AInstr(address) => Some(match address {
+                    Address::Literal(value) => *value,
+                    Address::Symbol(name) => doc.symbols.get(name).map_or(0, |symbol| symbol.address),
+                }).filter(|value| *value <= MAX_A_VALUE).map(Instruction::A),
+                Statement::CInstr(c_instr) => Some(Instruction::C(*c_instr)),
+                Statement::Label(_) => unreachable!("labels don't have a ROM address"),
+            };
+            // a label past the end of a full ROM has no encoding, the diagnostic on it says why
+            if let Some(instruction) = instruction {
+                let word = instruction.encode();
+                contents.push(format!("ROM[{rom_address}]: `{statement}` = `{word:016b}` (0x{word:04x})"));
+            }
+        }
+        if contents.is_empty() {
+            Value::Null
+        } else {
+            json!({"contents": {"kind": "markdown", "value": contents.join("\n\n")}})
+        }
+    }
+
+    fn completion(&self, doc: &Document, line: usize, character: usize) -> Value {
+        let before = &doc.line_text(line)[..character];
+        // kinds from the LSP spec: 6 = variable, 14 = keyword
+        let item = |label: String, kind: u8, detail: String| json!({"label": label, "kind": kind, "detail": detail});
+        let mut items = vec![];
+        if before.contains('@') {
+            for (name, symbol) in doc.symbols.iter() {
+                items.push(item(name.clone(), 6, describe_symbol(name, symbol)));
+            }
+        } else if before.contains(';') {
+            for jump in JumpIf::ALL.iter().filter(|jump| **jump != JumpIf::Null) {
+                items.push(item(jump.as_str().to_string(), 14, "jump".to_string()));
+            }
+        } else {
+            for comp in Comp::ALL {
+                items.push(item(comp.as_str(false).to_string(), 14, "comp".to_string()));
+                if comp.uses_x() {
+                    items.push(item(comp.as_str(true).to_string(), 14, "comp".to_string()));
+                }
+            }
+            if !before.contains('=') {
+                for dest in ["M", "D", "MD", "A", "AM", "AD", "AMD"] {
+                    items.push(item(format!("{dest}="), 14, "dest".to_string()));
+                }
+            }
+        }
+        Value::Array(items)
+    }
+
+    fn rename(&self, uri: &str, doc: &Document, line: usize, character: usize, new_name: &str) -> Result<Value, String> {
+        let occ = doc.occurrence_at(line, character).ok_or("there is no symbol here")?;
+        if doc.symbols.get(&occ.name).map(|symbol| symbol.kind) != Some(SymbolKind::Label) {
+            return Err(format!("`{}` is not a label", occ.name));
+        }
+        let valid_name = !new_name.is_empty()
+            && !new_name.starts_with(|c: char| c.is_ascii_digit())
+            && new_name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | ':'));
+        if !valid_name {
+            return Err(format!("`{new_name}` is not a valid symbol name"));
+        }
+        if doc.symbols.get(new_name).is_some() {
+            return Err(format!("`{new_name}` is already defined"));
+        }
+        let edits = doc.occurrences_of(&occ.name)
+            .map(|occ| json!({"range": doc.range(&occ.span), "newText": new_name}))
+            .collect::<Vec<_>>();
+        Ok(json!({"changes": {uri: edits}}))
+    }
+
+    // answers a request, returning its result or the error to reply with
+    fn handle_request(&mut self, method: &str, params: &Value) -> Result<Value, ResponseError> {
+        if method == "initialize" {
+            return Ok(json!({
+                "capabilities": {
+                    // full sync, the documents are tiny compared to what we do with them
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {"triggerCharacters": ["@", "=", ";"]},
+                    "renameProvider": true,
+                },
+                "serverInfo": {"name": "hack-asm-lsp"},
+            }));
+        }
+        if method == "shutdown" {
+            self.shutdown_requested = true;
+            return Ok(Value::Null);
+        }
+
+        let supported = ["textDocument/definition", "textDocument/references", "textDocument/hover", "textDocument/completion", "textDocument/rename"];
+        if !supported.contains(&method) {
+            return Err(ResponseError { code: METHOD_NOT_FOUND, message: format!("unsupported method `{method}`") });
+        }
+
+        let uri = params["textDocument"]["uri"].as_str().ok_or("missing document uri")?;
+        let doc = self.documents.get(uri).ok_or_else(|| format!("unknown document `{uri}`"))?;
+        let line = params["position"]["line"].as_u64().ok_or("missing position")? as usize;
+        let character = params["position"]["character"].as_u64().ok_or("missing position")? as usize;
+        let character = doc.byte_offset(line, character);
+        match method {
+            "textDocument/definition" => {
+                Ok(doc.occurrence_at(line, character)
+                    .and_then(|occ| doc.definition_of(&occ.name))
+                    .map_or(Value::Null, |def| doc.location(uri, &def.span)))
+            },
+            "textDocument/references" => {
+                let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+                Ok(doc.occurrence_at(line, character).map_or(json!([]), |occ| {
+                    doc.occurrences_of(&occ.name)
+                        .filter(|occ| include_declaration || !occ.is_declaration)
+                        .map(|occ| doc.location(uri, &occ.span))
+                        .collect()
+                }))
+            },
+            "textDocument/hover" => Ok(self.hover(doc, line, character)),
+            "textDocument/completion" => Ok(self.completion(doc, line, character)),
+            "textDocument/rename" => {
+                let new_name = params["newName"].as_str().ok_or("missing new name")?;
+                Ok(self.rename(uri, doc, line, character, new_name)?)
+            },
+            _ => unreachable!("unsupported methods are answered above"),
+        }
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Value, out: &mut impl Write) -> io::Result<()> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.clone(), Document::new(text.to_string()));
+                self.publish_diagnostics(&uri, out)?;
+            },
+            "textDocument/didChange" => {
+                // with full sync the last change holds the whole document
+                if let Some(text) = params["contentChanges"].as_array().and_then(|changes| changes.last()).and_then(|change| change["text"].as_str()) {
+                    self.documents.insert(uri.clone(), Document::new(text.to_string()));
+                    self.publish_diagnostics(&uri, out)?;
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish_diagnostics(&uri, out)?;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    // serves until the client sends `exit`, returning whether it shut down cleanly
+    pub fn run(&mut self, input: &mut impl BufRead, out: &mut impl Write) -> io::Result<bool> {
+        while let Some(message) = read_message(input)? {
+            let message = match message {
+                Ok(message) => message,
+                // there's no telling which request it was, so no id
+                Err(error) => {
+                    let error = json!({"code": PARSE_ERROR, "message": format!("invalid JSON: {error}")});
+                    write_message(out, &json!({"jsonrpc": "2.0", "id": null, "error": error}))?;
+                    continue;
+                },
+            };
+            let method = message["method"].as_str().unwrap_or_default();
+            if method == "exit" {
+                break;
+            }
+            match message.get("id") {
+                Some(id) => {
+                    let response = match self.handle_request(method, &message["params"]) {
+                        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                        Err(error) => json!({"jsonrpc": "2.0", "id": id, "error": {"code": error.code, "message": error.message}}),
+                    };
+                    write_message(out, &response)?;
+                },
+                None => self.handle_notification(method, &message["params"], out)?,
+            }
+        }
+        Ok(self.shutdown_requested)
+    }
+}
+
+// reads one `Content-Length`-framed message, or None once the input is closed
+// the body is an Err when it isn't valid JSON, that's for the client to hear about and doesn't end the session
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<serde_json::Result<Value>>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(length) = header.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse::<usize>().ok();
+        }
+    }
+    let content_length = content_length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; content_length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)))
+}
+
+fn write_message(out: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(body: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{body}", body.len())
+    }
+
+    #[test]
+    fn malformed_json_gets_a_parse_error_and_the_server_keeps_going() {
+        let input = [
+            framed("{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": "),
+            framed(r#"{"jsonrpc": "2.0", "id": 2, "method": "shutdown"}"#),
+            framed(r#"{"jsonrpc": "2.0", "method": "exit"}"#),
+        ].concat();
+        let mut out = vec![];
+        assert!(Server::default().run(&mut input.as_bytes(), &mut out).unwrap());
+
+        let mut replies = vec![];
+        let mut out = out.as_slice();
+        while let Some(reply) = read_message(&mut out).unwrap() {
+            replies.push(reply.unwrap());
+        }
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0]["id"], Value::Null);
+        assert_eq!(replies[0]["error"]["code"], PARSE_ERROR);
+        assert_eq!(replies[1]["id"], 2);
+        assert_eq!(replies[1]["result"], Value::Null);
+    }
+
+    #[test]
+    fn a_truncated_message_is_an_io_error() {
+        let input = "Content-Length: 100\r\n\r\n{}";
+        assert!(Server::default().run(&mut input.as_bytes(), &mut vec![]).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+
+use crate::instruction::{CInstr, Comp, JumpIf, SaveCompTo};
+use crate::lexer::{lex_line, Span, SyntaxError, Token, TokenKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Literal(u16),
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    Label(String),
+    AInstr(Address),
+    CInstr(CInstr),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Literal(value) => write!(f, "{value}"),
+            Address::Symbol(name) => f.write_str(name),
+        }
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Statement::Label(name) => write!(f, "({name})"),
+            Statement::AInstr(address) => write!(f, "@{address}"),
+            Statement::CInstr(c_instr) => write!(f, "{c_instr}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    // 0-based, same as in `Span`
+    pub line: usize,
+    pub statement: Option<(Statement, Span)>,
+    pub comment: Option<(String, Span)>,
+}
+
+impl SourceLine {
+    // labels don't take up a ROM address, everything else does
+    pub fn is_instruction(&self) -> bool {
+        matches!(self.statement, Some((Statement::AInstr(_) | Statement::CInstr(_), _)))
+    }
+}
+
+fn parse_address(value: &str, span: Span) -> Result<Address, SyntaxError> {
+    if value.starts_with(|c: char| c.is_ascii_digit()) {
+        // A instructions only have 15 bits for the value
+        match value.parse::<u16>() {
+            Ok(addr) if addr < 1 << 15 => Ok(Address::Literal(addr)),
+            _ => Err(SyntaxError { span, message: format!("`{value}` is not a valid 15-bit address") }),
+        }
+    } else {
+        Ok(Address::Symbol(value.to_string()))
+    }
+}
+
+fn parse_c_instr(tokens: &[Token]) -> Result<(CInstr, Span), SyntaxError> {
+    let mut c_instr = CInstr::default();
+    let span = Span { start: tokens[0].span.start, end: tokens[tokens.len() - 1].span.end, ..tokens[0].span };
+    let error = |token: &Token, message: String| SyntaxError { span: token.span, message };
+    for token in tokens {
+        match &token.kind {
+            TokenKind::Dest(string) => {
+                c_instr.save_comp_to = SaveCompTo::parse(string)
+                    .ok_or_else(|| error(token, format!("invalid destination `{string}`")))?;
+            },
+            TokenKind::Comp(string) => {
+                (c_instr.comp, c_instr.switch_a_for_m) = Comp::parse(string)
+                    .ok_or_else(|| error(token, format!("invalid computation `{string}`")))?;
+            },
+            TokenKind::Jump(string) => {
+                c_instr.jump_if = JumpIf::parse(string)
+                    .ok_or_else(|| error(token, format!("invalid jump `{string}`")))?;
+            },
+            _ => unreachable!("the lexer only groups dest/comp/jump together"),
+        }
+    }
+    Ok((c_instr, span))
+}
+
+pub fn parse_line(line: &str, line_no: usize) -> Result<SourceLine, SyntaxError> {
+    let mut tokens = lex_line(line, line_no)?;
+    let comment = match tokens.last() {
+        Some(Token { kind: TokenKind::Comment(text), span }) => {
+            let comment = (text.clone(), *span);
+            tokens.pop();
+            Some(comment)
+        },
+        _ => None,
+    };
+
+    let statement = match tokens.first() {
+        None => None,
+        Some(Token { kind: TokenKind::LabelDecl(name), span }) => Some((Statement::Label(name.clone()), *span)),
+        Some(Token { kind: TokenKind::AInstr(value), span }) => Some((Statement::AInstr(parse_address(value, *span)?), *span)),
+        Some(Token { kind: TokenKind::Directive(name), span }) => {
+            return Err(SyntaxError { span: *span, message: format!("unknown directive `.{name}`") });
+        },
+        Some(_) => {
+            let (c_instr, span) = parse_c_instr(&tokens)?;
+            Some((Statement::CInstr(c_instr), span))
+        },
+    };
+    Ok(SourceLine { line: line_no, statement, comment })
+}
+
+// parses a whole file, collecting every error instead of stopping at the first one
+pub fn parse_source(source: &str) -> (Vec<SourceLine>, Vec<SyntaxError>) {
+    let mut lines = vec![];
+    let mut errors = vec![];
+    for (line_no, line) in source.lines().enumerate() {
+        match parse_line(line, line_no) {
+            Ok(line) => lines.push(line),
+            Err(error) => errors.push(error),
+        }
+    }
+    (lines, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statement(line: &str) -> Option<Statement> {
+        parse_line(line, 0).unwrap().statement.map(|(statement, _)| statement)
+    }
+
+    #[test]
+    fn inline_comments_and_whitespace() {
+        let plain = statement("AM=M+1;JGT");
+        assert!(plain.is_some());
+        for line in ["AM=M+1;JGT // bump", "\tAM = M+1 ; JGT\t// bump", "  AM=M+1;JGT  "] {
+            assert_eq!(statement(line), plain, "{line:?}");
+        }
+        let line = parse_line("@i // the counter", 3).unwrap();
+        assert_eq!(line.statement.map(|(statement, _)| statement), Some(Statement::AInstr(Address::Symbol("i".to_string()))));
+        assert_eq!(line.comment.map(|(text, _)| text), Some("the counter".to_string()));
+    }
+
+    #[test]
+    fn crlf_source() {
+        let (lines, errors) = parse_source("(LOOP)\r\n@LOOP\r\n0;JMP // spin\r\n");
+        assert!(errors.is_empty());
+        let statements = lines.into_iter().filter_map(|line| line.statement).map(|(statement, _)| statement.to_string()).collect::<Vec<_>>();
+        assert_eq!(statements, ["(LOOP)", "@LOOP", "0;JMP"]);
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        let error = parse_line("    @32768", 5).unwrap_err();
+        assert_eq!((error.span.line, error.span.start), (5, 4));
+        assert!(parse_line("D=Q", 0).is_err());
+        assert!(parse_line(".data", 0).is_err());
+    }
+}
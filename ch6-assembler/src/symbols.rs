@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use crate::instruction::MAX_A_VALUE;
+use crate::lexer::SyntaxError;
+use crate::parser::{Address, SourceLine, Statement};
+
+// variables are allocated in RAM starting right after R15
+const FIRST_VARIABLE_ADDR: u16 = 16;
+pub const ROM_SIZE: u16 = 32768;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Predefined,
+    // points into ROM
+    Label,
+    // points into RAM
+    Variable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub address: u16,
+    pub kind: SymbolKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct SymbolTable {
+    symbols: HashMap<String, Symbol>,
+    next_variable: u16,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        let mut symbols = HashMap::new();
+        let mut predefine = |name: String, address: u16| {
+            symbols.insert(name, Symbol { address, kind: SymbolKind::Predefined });
+        };
+        for (name, address) in [("SP", 0), ("LCL", 1), ("ARG", 2), ("THIS", 3), ("THAT", 4), ("SCREEN", 16384), ("KBD", 24576)] {
+            predefine(name.to_string(), address);
+        }
+        for register in 0..16 {
+            predefine(format!("R{register}"), register);
+        }
+        Self { symbols, next_variable: FIRST_VARIABLE_ADDR }
+    }
+}
+
+impl SymbolTable {
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.symbols.get(name).copied()
+    }
+
+    // returns false if the name was already taken
+    pub fn add_label(&mut self, name: &str, rom_address: u16) -> bool {
+        if self.symbols.contains_key(name) {
+            return false;
+        }
+        self.symbols.insert(name.to_string(), Symbol { address: rom_address, kind: SymbolKind::Label });
+        true
+    }
+
+    // any symbol that isn't a label or predefined is a variable, so it gets the next free RAM address,
+    // as long as an A instruction can still hold it
+    pub fn resolve(&mut self, name: &str) -> Result<u16, String> {
+        if let Some(symbol) = self.symbols.get(name) {
+            // only a label after the last word of a full ROM gets that far
+            if symbol.address > MAX_A_VALUE {
+                return Err(format!("`{name}` points past the end of the {ROM_SIZE}-word ROM"));
+            }
+            return Ok(symbol.address);
+        }
+        if self.next_variable > MAX_A_VALUE {
+            return Err(format!("no room for variable `{name}`, variables can't go past RAM[{MAX_A_VALUE}]"));
+        }
+        let address = self.next_variable;
+        self.next_variable += 1;
+        self.symbols.insert(name.to_string(), Symbol { address, kind: SymbolKind::Variable });
+        Ok(address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Symbol)> {
+        self.symbols.iter()
+    }
+
+    // the names pointing at an address, sorted so the result doesn't depend on the hash map order;
+    // `kinds` picks between ROM (labels) and RAM (predefined and variables) names
+    pub fn names_at(&self, address: u16, kinds: &[SymbolKind]) -> Vec<&str> {
+        let mut names = self.symbols.iter()
+            .filter(|(_, symbol)| symbol.address == address && kinds.contains(&symbol.kind))
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    // first pass, one line at a time: record the ROM address of every label
+    pub fn scan_line(&mut self, line: &SourceLine, rom_address: &mut u16) -> Result<(), SyntaxError> {
+        match &line.statement {
+            Some((Statement::Label(name), span)) if !self.add_label(name, *rom_address) => {
+                Err(SyntaxError { span: *span, message: format!("`{name}` is already defined") })
+            },
+            Some((Statement::Label(_), _)) | None => Ok(()),
+            // only report the first instruction that doesn't fit
+            Some((_, span)) if *rom_address == ROM_SIZE => {
+                *rom_address += 1;
+                Err(SyntaxError { span: *span, message: format!("the program doesn't fit in the {ROM_SIZE}-word ROM") })
+            },
+            Some(_) => {
+                *rom_address = rom_address.saturating_add(1);
+                Ok(())
+            },
+        }
+    }
+
+    pub fn from_lines<'a>(lines: impl IntoIterator<Item = &'a SourceLine>) -> (Self, Vec<SyntaxError>) {
+        let mut table = Self::default();
+        let mut errors = vec![];
+        let mut rom_address: u16 = 0;
+        for line in lines {
+            if let Err(error) = table.scan_line(line, &mut rom_address) {
+                errors.push(error);
+            }
+        }
+        (table, errors)
+    }
+
+    pub fn resolve_address(&mut self, address: &Address) -> Result<u16, String> {
+        match address {
+            Address::Literal(value) => Ok(*value),
+            Address::Symbol(name) => self.resolve(name),
+        }
+    }
+}
@@ -25,6 +25,9 @@ fn main() {
     // 1st (idx:0) arg is always cwd, so we get the 2nd
     let path_arg = Path::new(cmd_args.get(1).expect("no runtime argument was provided!"));
     let filename = path_arg.file_stem().unwrap().to_str().unwrap();
+    if let Err(message) = transpiler::check_file_stem(filename) {
+        panic!("{message}");
+    }
     
     let instructions = lines_from_file(&path_arg);
     let mut parser = Parser::default();
@@ -2,6 +2,8 @@
 pub struct Command {
     pub cmd_as_obj: CommandAsObject,
     pub cmd_as_str: String,
+    // 1-based line in the .vm file
+    pub line: u32,
 }
 
 #[derive(Debug)]
@@ -12,12 +14,15 @@ pub enum CommandAsObject {
 
 #[derive(Debug)]
 pub enum MemorySegment {
-    SP,
     LCL,
     ARG,
     THIS,
     THAT,
     TEMP,
+    // THIS and THAT themselves
+    Pointer,
+    // per .vm file
+    Static,
     R13,
     R14,
     R15,
@@ -90,7 +95,8 @@ impl Parser {
 
     fn _match_segment(&self, word: &str) -> MemorySegment {
         match word {
-            "pointer" => MemorySegment::SP,
+            "pointer" => MemorySegment::Pointer,
+            "static" => MemorySegment::Static,
             "local" => MemorySegment::LCL,
             "argument" => MemorySegment::ARG,
             "this" => MemorySegment::THIS,
@@ -110,8 +116,8 @@ impl Parser {
             "pop" =>        OperationType::StackOperationType(StackOperationType::Pop),
             "add" =>        OperationType::ArithmeticOperationType(ArithmeticOperationType::ADD),
             "sub" =>        OperationType::ArithmeticOperationType(ArithmeticOperationType::SUB),
-            "negate" =>     OperationType::ArithmeticOperationType(ArithmeticOperationType::NEG),
-            "equal" =>      OperationType::ArithmeticOperationType(ArithmeticOperationType::EQ),
+            "neg" =>        OperationType::ArithmeticOperationType(ArithmeticOperationType::NEG),
+            "eq" =>         OperationType::ArithmeticOperationType(ArithmeticOperationType::EQ),
             "gt" =>         OperationType::ArithmeticOperationType(ArithmeticOperationType::GT),
             "lt" =>         OperationType::ArithmeticOperationType(ArithmeticOperationType::LT),
             "and" =>        OperationType::ArithmeticOperationType(ArithmeticOperationType::AND),
@@ -133,7 +139,8 @@ impl Parser {
                 },
                 OperationType::ArithmeticOperationType(op_type) => CommandAsObject::ArithmeticCommand { operationType: op_type },
             },
-            cmd_as_str: full_str.to_string()
+            cmd_as_str: full_str.to_string(),
+            line: self.current_line,
         }
     }
     
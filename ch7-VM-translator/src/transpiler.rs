@@ -3,38 +3,66 @@ use crate::parser::{self, MemorySegment};
 
 // const LCL_ADDR: str = "LCL";
 
-// the 2 are inversed because the stack starts on the top of the RAM and goes down
-const INCR_SP: &str = "M=M-1\n";
-const DECR_SP: &str = "M=M+1\n";
+// the stack grows up from RAM[256] and SP points at the next free slot
+
+// pushes D
+const PUSH_D: &str = "@SP\nA=M\nM=D\n@SP\nM=M+1";
+// pops into D, leaving A at the slot it came from
+const POP_D: &str = "@SP\nAM=M-1\nD=M";
 
 pub struct Transpiler {
-    to_file: Option<LineWriter<File>>
+    to_file: Option<LineWriter<File>>,
+    // the .vm file's name without the extension, for the comments that say where each command came
+    // from and for the labels of generated code
+    file_stem: String,
+    // how many comparisons were translated so far, to give each one its own labels
+    comparisons: u32,
     // stackBase: u16,
 }
 
+// the pointer to the segment's base, for the segments that have one
 pub fn match_segment(mem_segment: &parser::MemorySegment) -> Option<&'static str> {
     match mem_segment {
-        parser::MemorySegment::SP =>    Some("SP"),
         parser::MemorySegment::LCL =>   Some("LCL"),
         parser::MemorySegment::ARG =>   Some("ARG"),
         parser::MemorySegment::THIS =>  Some("THIS"),
         parser::MemorySegment::THAT =>  Some("THAT"),
-        parser::MemorySegment::TEMP =>  Some("TEMP"),
         parser::MemorySegment::R13 =>   Some("R13"),
         parser::MemorySegment::R14 =>   Some("R14"),
         parser::MemorySegment::R15 =>   Some("R15"),
-        parser::MemorySegment::CONST => None,
+        parser::MemorySegment::TEMP | parser::MemorySegment::Pointer | parser::MemorySegment::Static | parser::MemorySegment::CONST => None,
     }
 }
 
 impl Default for Transpiler {
     fn default() -> Self {
         Self {
-            to_file: None
+            to_file: None,
+            file_stem: String::new(),
+            comparisons: 0,
         }
     }
 }
 
+// the file stem goes into labels and static variables, so it has to make a Hack symbol, and one without
+// `.` or `$` so it can't run into function names and their labels
+pub fn check_file_stem(stem: &str) -> Result<(), String> {
+    if stem.is_empty() || stem.starts_with(|c: char| c.is_ascii_digit()) || !stem.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("`{stem}` can't be used in symbols, .vm file names have to be letters, digits and `_`, not starting with a digit"));
+    }
+    Ok(())
+}
+
+// leaves A at `index` words into the segment that `base` points at; only indices 0 and 1 get
+// there without going through D
+fn segment_address(base: &str, index: i16) -> String {
+    match index {
+        0 => format!("@{base}\nA=M"),
+        1 => format!("@{base}\nA=M+1"),
+        _ => format!("@{base}\nD=M\n@{index}\nA=D+A"),
+    }
+}
+
 impl Transpiler {
     pub fn new(to_file: &str) -> Self {
         Self {
@@ -47,7 +75,9 @@ impl Transpiler {
                 })
             } else {
                 None
-            }
+            },
+            file_stem: to_file.to_string(),
+            comparisons: 0,
         }
     }
 
@@ -73,86 +103,142 @@ impl Transpiler {
         }
     }
 
-    // write a commented line with the original, un-translated line, and then the actual parsed line(s)
+    // write a commented line with the original, un-translated line and where it came from
+    // (`// Foo.vm:12: push local 0`, the emulator reads these back), and then the actual parsed line(s)
     fn write_cmd(&mut self, cmd: &parser::Command) {
         
-        self._write_line(format!("// {}", &cmd.cmd_as_str).as_str());
-        self._write_line(self.transp_cmd_to_str(&cmd.cmd_as_obj).as_str());
+        self._write_line(format!("// {}.vm:{}: {}", self.file_stem, cmd.line, &cmd.cmd_as_str).as_str());
+        let asm = self.transp_cmd_to_str(&cmd.cmd_as_obj);
+        self._write_line(asm.as_str());
+    }
+
+    /* comparison of x (the operand in M, at RAM[SP-1]) with y (in D), leaving -1 or 0 in x's place.
+     x - y can overflow when the signs differ (32767 - -1 is negative), so when they do the sign of x
+     alone decides, and only same-sign operands get subtracted. `jump` is the jump taken on x - y when
+     the comparison holds, the labels are `{FileStem}$CMP.{n}.*`: file stems have no `.` (see `check_file_stem`) and function
+     names always do, so they can't collide with `function$label` labels nor function names */
+    fn transp_comparison(&mut self, jump: &str) -> String {
+        let label = format!("{}$CMP.{}", self.file_stem, self.comparisons);
+        self.comparisons += 1;
+        format!(
+            "@R13\nM=D\n@SP\nA=M-1\nD=M\n@{label}.X_NEG\nD;JLT\n\
+             @R13\nD=M\n@{label}.SAME_SIGN\nD;JGE\nD=1\n@{label}.TEST\n0;JMP\n\
+             ({label}.X_NEG)\n@R13\nD=M\n@{label}.SAME_SIGN\nD;JLT\nD=-1\n@{label}.TEST\n0;JMP\n\
+             ({label}.SAME_SIGN)\n@SP\nA=M-1\nD=M\n@R13\nD=D-M\n\
+             ({label}.TEST)\n@{label}.TRUE\nD;{jump}\nD=0\n@{label}.END\n0;JMP\n\
+             ({label}.TRUE)\nD=-1\n({label}.END)\n@SP\nA=M-1\nM=D"
+        )
     }
 
-    fn transp_cmd_to_str(&self, cmd: &parser::CommandAsObject) -> String {
+    // the symbol for `index` in a segment that sits at a fixed place: temp is RAM[5..=12], pointer is
+    // THIS and THAT, and static is a `{FileStem}.{index}` variable the assembler allocates
+    fn fixed_address(&self, mem_segment: &parser::MemorySegment, index: i16) -> Option<String> {
+        match mem_segment {
+            parser::MemorySegment::TEMP => match index {
+                0..=7 => Some((5 + index).to_string()),
+                _ => panic!("`temp {index}` is out of range, temp has 8 words"),
+            },
+            parser::MemorySegment::Pointer => match index {
+                0 => Some("THIS".to_string()),
+                1 => Some("THAT".to_string()),
+                _ => panic!("`pointer {index}` is out of range, it's either 0 (THIS) or 1 (THAT)"),
+            },
+            parser::MemorySegment::Static => Some(format!("{}.{index}", self.file_stem)),
+            _ => None,
+        }
+    }
+
+    fn transp_cmd_to_str(&mut self, cmd: &parser::CommandAsObject) -> String {
         
         let mut out_string = String::new();
         match cmd {
             parser::CommandAsObject::StackCommand { operationType, memorySegment, index } => {
                 /* -- for POP:
+                 - take the top of the stack
                  @SP
-                 - decrement stack pointer
-                 M=M-1    
-                 A=M+1 - move to addr referenced by sp (we have to compensate for the decrement)
+                 AM=M-1 - decrement stack pointer, which then points at the top
                  D=M - get data from memory
                  - now we gotta do the following: @{SEGMENT+INDEX}
                  @{SEGMENT}
-                 A=A+{INDEX}
+                 A=M - or A=M+1 for index 1
+                 M=D
+                 - but past index 1 the address needs D, which holds the data by then, so it's
+                 computed first and kept in R13:
+                 @{SEGMENT}
+                 D=M
+                 @{INDEX}
+                 D=D+A
+                 @R13
+                 M=D
+                 ..pop into D..
+                 @R13
+                 A=M
+                 M=D
+                 - temp, pointer and static need none of that, they're at a fixed address:
+                 @{ADDRESS}
                  M=D
                 */ /*
                  -- for PUSH:
-                 - get data from SEGMENT+INDEX 
+                 - get data from SEGMENT+INDEX
                  @{SEGMENT}
-                 A=A+{INDEX}
                  D=M
-                 - ..then go to top of the stack
+                 @{INDEX}
+                 A=D+A - or just A=M / A=M+1 for index 0 / 1
+                 D=M
+                 - ..then go to the free slot on top of the stack
                  @SP
-                 - increment stack pointer
-                 M=M+1
                  A=M - move to addr referenced by sp
-                 M=D - then finally "push" data to stack
+                 M=D - "push" data to stack
+                 @SP
+                 M=M+1 - then finally increment stack pointer
                  */
 
                 
                 let index_as_str = index.to_string();
-                out_string.push_str(match operationType {
-                    parser::StackOperationType::Pop => {
-                        match_segment(memorySegment).map_or_else(
-                            ||panic!("DON'T USE `CONST` ON A `POP` COMMAND WTF"), 
-                            |mem_segment|
-                            format!("@SP\nM=M-1\nA=M+1\nD=M\n@{mem_segment}\nA=A+{index}\nM=D")
-                        )
-                    },
-                    parser::StackOperationType::Push => {
-                        match_segment(memorySegment).map_or_else(
-                            || // if memorySegment == CONST
-                            format!("@{index}\nD=A\n@SP\nM=M+1\nA=M\nM=D"), 
-                            |mem_segment|
-                            format!("@{mem_segment}\nA=A+{index}\nD=M\n@SP\nM=M+1\nA=M\nM=D")
-                        )
+                let code = match (match_segment(memorySegment), self.fixed_address(memorySegment, *index), operationType) {
+                    (Some(mem_segment), _, parser::StackOperationType::Pop) => match index {
+                        0 | 1 => format!("{POP_D}\n{}\nM=D", segment_address(mem_segment, *index)),
+                        _ => format!("@{mem_segment}\nD=M\n@{index}\nD=D+A\n@R13\nM=D\n{POP_D}\n@R13\nA=M\nM=D"),
                     },
-                }.as_str());
+                    (Some(mem_segment), _, parser::StackOperationType::Push) =>
+                        format!("{}\nD=M\n{PUSH_D}", segment_address(mem_segment, *index)),
+                    (None, Some(address), parser::StackOperationType::Pop) => format!("{POP_D}\n@{address}\nM=D"),
+                    (None, Some(address), parser::StackOperationType::Push) => format!("@{address}\nD=M\n{PUSH_D}"),
+                    // if memorySegment == CONST
+                    (None, None, parser::StackOperationType::Pop) => panic!("DON'T USE `CONST` ON A `POP` COMMAND WTF"),
+                    (None, None, parser::StackOperationType::Push) => format!("@{index}\nD=A\n{PUSH_D}"),
+                };
+                out_string.push_str(&code);
+            },
+            // unary: on the top of the stack, in place
+            parser::CommandAsObject::ArithmeticCommand { operationType: parser::ArithmeticOperationType::NEG } => {
+                out_string.push_str("@SP\nA=M-1\nM=-M");
             },
+            parser::CommandAsObject::ArithmeticCommand { operationType: parser::ArithmeticOperationType::NOT } => {
+                out_string.push_str("@SP\nA=M-1\nM=!M");
+            },
+            // binary: pop two, push one
             parser::CommandAsObject::ArithmeticCommand { operationType } => {
-                out_string.push_str("@SP\nM=M-1\nA=M+1\nD=M\nA=A-1\n    "); 
-                /* 
-                1st operand: M (@SP)
-                2nd operand: D (@SP+1)
+                out_string.push_str(POP_D);
+                out_string.push_str("\nA=A-1\n");
+                /*
+                1st operand: M (@SP-1)
+                2nd operand: D (@SP)
                 output -> M */
-                out_string.push_str(
-            match operationType {
-                        // arithmetic
-                        parser::ArithmeticOperationType::ADD =>  "M=M+D",
-                        parser::ArithmeticOperationType::SUB =>  "M=M-D",
-                        parser::ArithmeticOperationType::NEG =>  "M=0-M",
-                        // TODO: write these also
-                        // comparison
-                        parser::ArithmeticOperationType::EQ =>   "",
-                        parser::ArithmeticOperationType::GT =>   "",
-                        parser::ArithmeticOperationType::LT =>   "",
-                        // logical
-                        parser::ArithmeticOperationType::AND =>  "",
-                        parser::ArithmeticOperationType::OR =>   "",
-                        parser::ArithmeticOperationType::NOT =>  "",
-
-                    }
-                )
+                let operation = match operationType {
+                    // arithmetic
+                    parser::ArithmeticOperationType::ADD =>  "M=M+D".to_string(),
+                    parser::ArithmeticOperationType::SUB =>  "M=M-D".to_string(),
+                    // comparison
+                    parser::ArithmeticOperationType::EQ =>   self.transp_comparison("JEQ"),
+                    parser::ArithmeticOperationType::GT =>   self.transp_comparison("JGT"),
+                    parser::ArithmeticOperationType::LT =>   self.transp_comparison("JLT"),
+                    // logical
+                    parser::ArithmeticOperationType::AND =>  "M=D&M".to_string(),
+                    parser::ArithmeticOperationType::OR =>   "M=D|M".to_string(),
+                    parser::ArithmeticOperationType::NEG | parser::ArithmeticOperationType::NOT => unreachable!("unary operations are matched above"),
+                };
+                out_string.push_str(&operation);
             },
         }
         out_string
@@ -166,4 +252,63 @@ impl Transpiler {
         self.exit();
 
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    // VM code that pushes any 16-bit value, as constants only go up to 32767
+    fn push(value: i16) -> String {
+        match value {
+            i16::MIN => "push constant 32767\nneg\npush constant 1\nsub".to_string(),
+            0.. => format!("push constant {value}"),
+            _ => format!("push constant {}\nneg", -value),
+        }
+    }
+
+    // translates `vm`, runs it with the stack at 256 and returns what's left on top of it
+    fn run(vm: &str) -> i16 {
+        let commands = Parser::default().parse_str_vec_to_cmd(&mut vm.lines());
+        let mut transpiler = Transpiler { file_stem: "Test".to_string(), ..Default::default() };
+        let mut asm = "@256\nD=A\n@SP\nM=D".to_string();
+        for cmd in &commands {
+            asm.push('\n');
+            asm.push_str(&transpiler.transp_cmd_to_str(&cmd.cmd_as_obj));
+        }
+        asm.push_str("\n(END)\n@END\n0;JMP");
+        let mut cpu = emulator::Cpu::new(assembler::assemble(&asm).unwrap());
+        cpu.run_until_halt().unwrap();
+        let sp = cpu.read(0);
+        assert_eq!(sp, 257, "the stack should hold just the result");
+        cpu.read(sp - 1) as i16
+    }
+
+    #[test]
+    fn comparisons_at_the_ends_of_the_range() {
+        let values = [i16::MIN, i16::MIN + 1, -1, 0, 1, i16::MAX - 1, i16::MAX];
+        for x in values {
+            for y in values {
+                for (op, expected) in [("eq", x == y), ("gt", x > y), ("lt", x < y)] {
+                    let result = run(&format!("{}\n{}\n{op}", push(x), push(y)));
+                    assert_eq!(result, if expected { -1 } else { 0 }, "{x} {op} {y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn file_stems_have_to_make_symbols() {
+        for stem in ["SimpleAdd", "Main", "test_2"] {
+            assert!(check_file_stem(stem).is_ok(), "{stem}");
+        }
+        for stem in ["my-test", "2nd", "Foo.bar", "a$b", "", "é"] {
+            assert!(check_file_stem(stem).is_err(), "{stem}");
+        }
+    }
+
+    #[test]
+    fn every_comparison_gets_its_own_labels() {
+        assert_eq!(run("push constant 1\npush constant 2\nlt\npush constant 2\npush constant 1\nlt\neq\nnot"), -1);
+    }
+}